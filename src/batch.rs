@@ -0,0 +1,139 @@
+use std::fs::read_to_string;
+use std::path::Path;
+
+// Runs the commands listed in a script file one per line, with the same
+// syntax as the interactive prompt. Empty lines and lines starting with '#'
+// are skipped. Every command is passed to run_step, which returns whether it
+// succeeded. Returns true when every command succeeded.
+pub fn run_script(path: &Path, run_step: impl FnMut(&str) -> bool, keep_going: bool) -> bool {
+    let script = match read_to_string(path) {
+        Ok(script) => script,
+        Err(error) => {
+            eprintln!("Failed to read script '{}': {error}", path.display());
+            return false;
+        }
+    };
+
+    let steps: Vec<(usize, &str)> = script
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+
+    run_commands(&steps, run_step, keep_going)
+}
+
+// Runs the given commands, each with the line number it came from, and
+// prints a summary. Returns true when every command up to the end of the
+// script or up to a `quit` succeeded.
+pub fn run_commands(
+    steps: &[(usize, &str)],
+    mut run_step: impl FnMut(&str) -> bool,
+    keep_going: bool,
+) -> bool {
+    let mut results = Vec::new();
+    let mut quit = false;
+
+    for (step, (line_number, cmd)) in steps.iter().enumerate() {
+        println!("[{}/{}] {cmd}", step + 1, steps.len());
+
+        // the caller leaves the bootloader once the summary is printed
+        if cmd.split_whitespace().next() == Some("quit") {
+            results.push(true);
+            quit = true;
+            break;
+        }
+
+        let success = run_step(cmd);
+        results.push(success);
+        if !success {
+            eprintln!("Step {} (line {line_number}) failed: {cmd}", step + 1);
+            if !keep_going {
+                break;
+            }
+        }
+    }
+
+    println!();
+    println!("Script summary:");
+    for (step, (_, cmd)) in steps.iter().enumerate() {
+        let status = match results.get(step) {
+            Some(true) => "OK",
+            Some(false) => "FAILED",
+            None => "SKIPPED",
+        };
+        println!("{:>3}. {status:<7} {cmd}", step + 1);
+    }
+
+    let failed = results.iter().filter(|success| !**success).count();
+    println!(
+        "{} of {} steps succeeded, {failed} failed",
+        results.len() - failed,
+        steps.len()
+    );
+
+    failed == 0 && (quit || results.len() == steps.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // runs the steps and returns the script result and the commands that
+    // were passed to run_step
+    fn run(steps: &[&str], failing: &[&str], keep_going: bool) -> (bool, Vec<String>) {
+        let steps: Vec<(usize, &str)> = steps
+            .iter()
+            .enumerate()
+            .map(|(index, cmd)| (index + 1, *cmd))
+            .collect();
+        let mut executed = Vec::new();
+        let success = run_commands(
+            &steps,
+            |cmd| {
+                executed.push(cmd.to_string());
+                !failing.contains(&cmd)
+            },
+            keep_going,
+        );
+        (success, executed)
+    }
+
+    #[test]
+    fn all_steps_succeeding() {
+        assert_eq!(
+            run(&["version", "dev_id"], &[], false),
+            (true, vec!["version".to_string(), "dev_id".to_string()])
+        );
+        assert_eq!(run(&[], &[], false), (true, vec![]));
+    }
+
+    #[test]
+    fn stops_at_the_first_failure() {
+        assert_eq!(
+            run(&["version", "erase 9 1", "dev_id"], &["erase 9 1"], false),
+            (false, vec!["version".to_string(), "erase 9 1".to_string()])
+        );
+    }
+
+    #[test]
+    fn keep_going_runs_every_step_but_still_fails() {
+        let (success, executed) = run(&["version", "erase 9 1", "dev_id"], &["erase 9 1"], true);
+        assert!(!success);
+        assert_eq!(executed.len(), 3);
+    }
+
+    #[test]
+    fn quit_ends_the_script_successfully() {
+        assert_eq!(
+            run(&["version", "quit", "dev_id"], &[], false),
+            (true, vec!["version".to_string()])
+        );
+        // a failure before the quit is still reported
+        assert_eq!(
+            run(&["version", "quit"], &["version"], true),
+            (false, vec!["version".to_string()])
+        );
+    }
+}
@@ -0,0 +1,289 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::fs::remove_file;
+use std::path::PathBuf;
+
+use crate::device::{sector_index, FLASH_SECTORS};
+use crate::image::{save_image, ImageFormat};
+use crate::memory::MemoryImage;
+use crate::protocol::MAX_DATA_CHUNK_SIZE;
+use crate::session::Session;
+
+// What to do when a write would need to change flash bits from 0 to 1,
+// which is only possible by erasing
+#[derive(Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BitCheck {
+    // write without reading the flash first
+    Off,
+    // refuse the write and list the addresses
+    Abort,
+    // erase the sectors containing the addresses before writing
+    Erase,
+    // back up the sectors containing the addresses, erase them and write
+    // them back merged with the image
+    Preserve,
+}
+
+// Reads the flash the image is written to and checks that every bit set in
+// the image is still set in flash. Depending on the mode the write is
+// refused or the affected sectors are erased. Returns the address ranges of
+// the sectors that were already rewritten with the image in preserve mode.
+pub fn check_bit_transitions(
+    session: &mut Session,
+    image: &MemoryImage,
+) -> Result<Vec<(u32, u32)>, String> {
+    const MAX_REPORTED_ADDRESSES: usize = 10;
+
+    let mode = session.settings.bit_check;
+    if let BitCheck::Off = mode {
+        return Ok(Vec::new());
+    }
+
+    let mut transitions = Vec::new();
+    for segment in image.segments() {
+        let current = session.read_memory(segment.address, segment.data.len())?;
+        for (offset, (new, old)) in segment.data.iter().zip(&current).enumerate() {
+            if new & !old != 0 {
+                transitions.push((segment.address + offset as u32, *old, *new));
+            }
+        }
+    }
+
+    if transitions.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    if let BitCheck::Abort = mode {
+        let mut error = format!(
+            "The write needs {} bytes to change from 0 to 1, erase the flash first!",
+            transitions.len()
+        );
+        for (address, old, new) in transitions.iter().take(MAX_REPORTED_ADDRESSES) {
+            error += &format!("\n  0x{address:08X}: flash 0x{old:02X}, writing 0x{new:02X}");
+        }
+        if transitions.len() > MAX_REPORTED_ADDRESSES {
+            error += "\n  ...";
+        }
+        return Err(error);
+    }
+
+    let mut sectors: Vec<usize> = transitions
+        .iter()
+        .filter_map(|(address, _, _)| sector_index(*address))
+        .collect();
+    sectors.dedup();
+
+    let mut rewritten = Vec::new();
+    for sector in sectors {
+        let start = FLASH_SECTORS[sector].start;
+        if let BitCheck::Preserve = mode {
+            rewrite_sector(session, sector, image)?;
+            rewritten.push((start, start + FLASH_SECTORS[sector].size));
+        } else {
+            println!(
+                "Erasing sector {sector} (0x{start:08X}, {}K) before writing, its other contents are lost",
+                FLASH_SECTORS[sector].size / 1024
+            );
+            session.erase_sectors(sector as u8, 1)?;
+        }
+    }
+
+    Ok(rewritten)
+}
+
+// Backs up the sector to a local file, erases it and writes it back with the
+// bytes of the image in it replaced. The backup is removed once the sector
+// reads back as expected and kept otherwise.
+fn rewrite_sector(session: &mut Session, sector: usize, image: &MemoryImage) -> Result<(), String> {
+    let start = FLASH_SECTORS[sector].start;
+    let size = FLASH_SECTORS[sector].size;
+    println!(
+        "Rewriting sector {sector} (0x{start:08X}, {}K) with its other contents preserved",
+        size / 1024
+    );
+
+    // a backup left by an earlier failed rewrite may be the only good copy
+    // of the sector, it is never overwritten
+    let backup_path = PathBuf::from(format!("sector{sector}_{start:08X}.backup.hex"));
+    if backup_path.exists() {
+        return Err(format!(
+            "Backup '{}' of an earlier failed rewrite of sector {sector} exists! \
+             Restore it with --bit-check erase and 'write {}' or delete it first.",
+            backup_path.display(),
+            backup_path.display()
+        ));
+    }
+
+    let mut contents = session.read_memory(start, size as usize)?;
+
+    let mut backup = MemoryImage::new();
+    backup.add_segment(start, contents.clone())?;
+    save_image(&backup_path, ImageFormat::IntelHex, &backup, 0xFF)?;
+    println!("Sector {sector} backed up to '{}'", backup_path.display());
+
+    for segment in image.segments() {
+        let overlap_start = segment.address.max(start);
+        let overlap_end = segment.end().min(start + size);
+        if overlap_start >= overlap_end {
+            continue;
+        }
+        let source = (overlap_start - segment.address) as usize;
+        let target = (overlap_start - start) as usize;
+        let length = (overlap_end - overlap_start) as usize;
+        contents[target..target + length].copy_from_slice(&segment.data[source..source + length]);
+    }
+
+    let keep_backup = |error: String| {
+        format!(
+            "{error}\nSector {sector} backup kept in '{}', restore it with --bit-check erase \
+             and 'write {}', then delete it",
+            backup_path.display(),
+            backup_path.display()
+        )
+    };
+
+    session
+        .erase_sectors(sector as u8, 1)
+        .map_err(keep_backup)?;
+
+    // erased flash already reads as 0xFF, so erased chunks are skipped
+    for (index, chunk) in contents.chunks(MAX_DATA_CHUNK_SIZE as usize).enumerate() {
+        if chunk.iter().all(|byte| *byte == 0xFF) {
+            continue;
+        }
+        let address = start + (index * MAX_DATA_CHUNK_SIZE as usize) as u32;
+        session.write_memory(address, chunk).map_err(keep_backup)?;
+    }
+
+    let read_back = session
+        .read_memory(start, size as usize)
+        .map_err(keep_backup)?;
+    if read_back != contents {
+        return Err(keep_backup(format!(
+            "Sector {sector} does not read back as written!"
+        )));
+    }
+
+    let _ = remove_file(&backup_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fakeport::fake_session;
+    use crate::protocol::{CMD_BL_FLASH_ERASE, CMD_BL_MEM_READ};
+    use std::path::Path;
+
+    // sector 2 starts at 0x08008000
+    const SECTOR_2_OFFSET: usize = 0x8000;
+
+    fn image(address: u32, data: &[u8]) -> MemoryImage {
+        let mut image = MemoryImage::new();
+        image.add_segment(address, data.to_vec()).unwrap();
+        image
+    }
+
+    #[test]
+    fn clearing_bits_needs_no_erase() {
+        let (mut session, bootloader) = fake_session();
+        bootloader.lock().unwrap().flash[SECTOR_2_OFFSET..SECTOR_2_OFFSET + 2]
+            .copy_from_slice(&[0xF0, 0x0F]);
+        assert!(check_bit_transitions(&mut session, &image(0x0800_8000, &[0x30, 0x00])).is_ok());
+    }
+
+    #[test]
+    fn abort_lists_the_bytes_needing_an_erase() {
+        let (mut session, bootloader) = fake_session();
+        bootloader.lock().unwrap().flash[SECTOR_2_OFFSET + 1] = 0x00;
+        let error =
+            check_bit_transitions(&mut session, &image(0x0800_8000, &[0x00, 0x01])).unwrap_err();
+        assert!(
+            error.contains("1 bytes") && error.contains("0x08008001: flash 0x00, writing 0x01"),
+            "{error}"
+        );
+        let erased = bootloader.lock().unwrap().flash[SECTOR_2_OFFSET + 1];
+        assert_eq!(erased, 0x00);
+    }
+
+    #[test]
+    fn erase_mode_erases_the_affected_sectors() {
+        let (mut session, bootloader) = fake_session();
+        session.settings.bit_check = BitCheck::Erase;
+        bootloader.lock().unwrap().flash[SECTOR_2_OFFSET..SECTOR_2_OFFSET + 4].fill(0x00);
+        let rewritten =
+            check_bit_transitions(&mut session, &image(0x0800_8000, &[0x12, 0x34])).unwrap();
+        assert!(rewritten.is_empty());
+
+        let bootloader = bootloader.lock().unwrap();
+        assert!(bootloader.flash[SECTOR_2_OFFSET..SECTOR_2_OFFSET + 4]
+            .iter()
+            .all(|byte| *byte == 0xFF));
+        let erases: Vec<&Vec<u8>> = bootloader
+            .frames
+            .iter()
+            .filter(|frame| frame[1] == CMD_BL_FLASH_ERASE.code)
+            .collect();
+        assert_eq!(erases.len(), 1);
+        assert_eq!(erases[0][2..4], [2, 1]);
+    }
+
+    #[test]
+    fn off_mode_does_not_read_the_flash() {
+        let (mut session, bootloader) = fake_session();
+        session.settings.bit_check = BitCheck::Off;
+        bootloader.lock().unwrap().flash[SECTOR_2_OFFSET] = 0x00;
+        assert!(check_bit_transitions(&mut session, &image(0x0800_8000, &[0xFF])).is_ok());
+        assert!(!bootloader
+            .lock()
+            .unwrap()
+            .frames
+            .iter()
+            .any(|frame| frame[1] == CMD_BL_MEM_READ.code));
+    }
+
+    #[test]
+    fn preserve_mode_rewrites_the_sector_with_its_other_contents() {
+        // sector 3 starts at 0x0800C000
+        const SECTOR_3_OFFSET: usize = 0xC000;
+
+        let (mut session, bootloader) = fake_session();
+        session.settings.bit_check = BitCheck::Preserve;
+        {
+            let mut bootloader = bootloader.lock().unwrap();
+            bootloader.flash[SECTOR_3_OFFSET..SECTOR_3_OFFSET + 4].copy_from_slice(&[1, 2, 3, 4]);
+            bootloader.flash[SECTOR_3_OFFSET + 0x3FFF] = 0x55;
+        }
+
+        let rewritten =
+            check_bit_transitions(&mut session, &image(0x0800_C001, &[0xAA, 0xBB])).unwrap();
+        assert_eq!(rewritten, vec![(0x0800_C000, 0x0801_0000)]);
+
+        let flash = &bootloader.lock().unwrap().flash;
+        assert_eq!(
+            flash[SECTOR_3_OFFSET..SECTOR_3_OFFSET + 4],
+            [1, 0xAA, 0xBB, 4]
+        );
+        assert_eq!(flash[SECTOR_3_OFFSET + 0x3FFF], 0x55);
+        assert!(!Path::new("sector3_0800C000.backup.hex").exists());
+    }
+
+    #[test]
+    fn existing_backup_is_never_overwritten() {
+        let backup = Path::new("sector1_08004000.backup.hex");
+        std::fs::write(backup, "backup").unwrap();
+
+        let (mut session, bootloader) = fake_session();
+        session.settings.bit_check = BitCheck::Preserve;
+        bootloader.lock().unwrap().flash[0x4000] = 0x00;
+        let result = check_bit_transitions(&mut session, &image(0x0800_4000, &[0x01]));
+
+        let content = std::fs::read_to_string(backup).unwrap();
+        std::fs::remove_file(backup).unwrap();
+        let error = result.unwrap_err();
+        assert!(error.contains("earlier failed rewrite"), "{error}");
+        assert_eq!(content, "backup");
+        assert_eq!(bootloader.lock().unwrap().flash[0x4000], 0x00);
+    }
+}
@@ -0,0 +1,79 @@
+use crate::protocol::{find_bootloader_command, BOOTLOADER_COMMANDS, CMD_BL_GET_HELP};
+use crate::session::Session;
+
+// Queries the commands the bootloader supports. Until this succeeds every
+// command is assumed to be supported.
+pub fn negotiate_capabilities(session: &mut Session) {
+    let supported = match session.execute_command(&CMD_BL_GET_HELP, &[], &[]) {
+        Ok(supported) => supported,
+        Err(error) => {
+            eprintln!("Failed to query the supported commands: {error}");
+            eprintln!("Assuming the bootloader supports all commands.");
+            return;
+        }
+    };
+
+    let unsupported: Vec<&str> = BOOTLOADER_COMMANDS
+        .iter()
+        .filter(|command| !supported.contains(&command.code))
+        .map(|command| command.name)
+        .collect();
+    if !unsupported.is_empty() {
+        println!(
+            "Commands not supported by the bootloader: {}",
+            unsupported.join(", ")
+        );
+    }
+
+    let unknown: Vec<String> = supported
+        .iter()
+        .filter(|code| find_bootloader_command(**code).is_none())
+        .map(|code| format!("0x{code:02X}"))
+        .collect();
+    if !unknown.is_empty() {
+        eprintln!(
+            "Warning: the bootloader implements commands unknown to this tool: {}",
+            unknown.join(", ")
+        );
+    }
+
+    session.supported_commands = Some(supported);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fakeport::fake_session;
+    use crate::protocol::{CMD_BL_FLASH_ERASE, CMD_BL_GET_VER};
+
+    #[test]
+    fn commands_not_advertised_are_refused() {
+        let (mut session, bootloader) = fake_session();
+        bootloader
+            .lock()
+            .unwrap()
+            .replies
+            .push_back(vec![0xA1, 0xA2, 0xA3]);
+
+        negotiate_capabilities(&mut session);
+        assert!(session.is_command_supported(CMD_BL_GET_VER.code));
+        assert!(!session.is_command_supported(CMD_BL_FLASH_ERASE.code));
+
+        let frames = bootloader.lock().unwrap().frames.len();
+        let error = session
+            .execute_command(&CMD_BL_FLASH_ERASE, &[2, 1], &[])
+            .unwrap_err();
+        assert!(error.contains("not supported"), "{error}");
+        assert_eq!(bootloader.lock().unwrap().frames.len(), frames);
+    }
+
+    #[test]
+    fn every_command_is_assumed_supported_when_the_query_fails() {
+        let (mut session, bootloader) = fake_session();
+        bootloader.lock().unwrap().replies.push_back(vec![]);
+
+        negotiate_capabilities(&mut session);
+        assert_eq!(session.supported_commands, None);
+        assert!(session.is_command_supported(CMD_BL_FLASH_ERASE.code));
+    }
+}
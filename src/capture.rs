@@ -0,0 +1,499 @@
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+use std::cell::Cell;
+use std::fs::{read_to_string, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::trace::to_hex;
+
+// Capture files are text files with one event per line:
+//
+//     # stm32-flash-programmer-cli capture v1
+//     0.000160 TX 05 A1 EE 14 13 F5
+//     0.000447 RX BB 01
+//     0.000457 RX 10
+//     0.000470 CMD version
+//
+// CMD lines hold the commands typed in or read from a script with all their
+// arguments, also the ones entered at the prompts, and follow the frames the
+// command exchanged. TX lines hold the frames sent to the bootloader and RX
+// lines the bytes received. Timestamps are seconds since the start of the
+// capture.
+const CAPTURE_HEADER: &str = "# stm32-flash-programmer-cli capture v1";
+
+pub struct Capture {
+    file: File,
+    start: Instant,
+}
+
+pub fn create_capture(path: &Path) -> Result<Capture, String> {
+    let mut file = File::create(path).map_err(|error| {
+        format!(
+            "Failed to create capture file '{}': {error}",
+            path.display()
+        )
+    })?;
+    let _ = writeln!(file, "{CAPTURE_HEADER}");
+
+    Ok(Capture {
+        file,
+        start: Instant::now(),
+    })
+}
+
+impl Capture {
+    fn write_event(&mut self, kind: &str, data: &str) {
+        let elapsed = self.start.elapsed();
+        let _ = writeln!(
+            self.file,
+            "{}.{:06} {kind} {data}",
+            elapsed.as_secs(),
+            elapsed.subsec_micros()
+        );
+    }
+
+    pub fn command(&mut self, cmd: &str) {
+        self.write_event("CMD", cmd);
+    }
+
+    pub fn tx(&mut self, frame: &[u8]) {
+        self.write_event("TX", &to_hex(frame));
+    }
+
+    pub fn rx(&mut self, bytes: &[u8]) {
+        if !bytes.is_empty() {
+            self.write_event("RX", &to_hex(bytes));
+        }
+    }
+}
+
+// A frame sent to the bootloader and the bytes received until the next one
+struct Exchange {
+    tx: Vec<u8>,
+    rx: Vec<u8>,
+}
+
+pub struct CaptureFile {
+    pub commands: Vec<String>,
+    exchanges: Vec<Exchange>,
+}
+
+fn parse_hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    hex.split_whitespace()
+        .map(|byte| u8::from_str_radix(byte, 16).ok())
+        .collect()
+}
+
+pub fn load_capture(path: &Path) -> Result<CaptureFile, String> {
+    let content = read_to_string(path)
+        .map_err(|error| format!("Failed to read capture file '{}': {error}", path.display()))?;
+
+    let mut capture = CaptureFile {
+        commands: Vec::new(),
+        exchanges: Vec::new(),
+    };
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid_line = || format!("Invalid capture file line {}: '{line}'", index + 1);
+
+        // the timestamp is not needed for replaying
+        let mut fields = line.splitn(3, ' ').skip(1);
+        let kind = fields.next().ok_or_else(invalid_line)?;
+        let data = fields.next().unwrap_or("");
+
+        match kind {
+            "CMD" => capture.commands.push(data.to_string()),
+            "TX" => capture.exchanges.push(Exchange {
+                tx: parse_hex_bytes(data).ok_or_else(invalid_line)?,
+                rx: Vec::new(),
+            }),
+            "RX" => {
+                let bytes = parse_hex_bytes(data).ok_or_else(invalid_line)?;
+                match capture.exchanges.last_mut() {
+                    Some(exchange) => exchange.rx.extend(bytes),
+                    None => return Err(invalid_line()),
+                }
+            }
+            _ => return Err(invalid_line()),
+        }
+    }
+
+    Ok(capture)
+}
+
+// Serial port that plays back the bootloader side of a capture. Every frame
+// written moves on to the next recorded exchange and reads return the bytes
+// received after that frame, or time out once they are used up. Like a real
+// port a timed out read only returns after the timeout, so loops probing
+// until a deadline, e.g. while waiting for the bootloader to come back after
+// a jump, send as many frames as they did while recording.
+pub struct ReplayPort {
+    exchanges: Vec<Exchange>,
+    current: Option<usize>,
+    tx_position: usize,
+    tx_mismatch: bool,
+    rx_position: Cell<usize>,
+    timeout: Duration,
+}
+
+impl ReplayPort {
+    pub fn new(capture: CaptureFile) -> Self {
+        ReplayPort {
+            exchanges: capture.exchanges,
+            current: None,
+            tx_position: 0,
+            tx_mismatch: false,
+            rx_position: Cell::new(0),
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+impl io::Write for ReplayPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let frame_complete = self
+            .current
+            .is_none_or(|current| self.tx_position >= self.exchanges[current].tx.len());
+        if frame_complete {
+            let next = self.current.map_or(0, |current| current + 1);
+            if next >= self.exchanges.len() {
+                return Err(io::Error::other("end of the capture reached"));
+            }
+            self.current = Some(next);
+            self.tx_position = 0;
+            self.tx_mismatch = false;
+            self.rx_position.set(0);
+        }
+
+        let current = self.current.unwrap();
+        let recorded = &self.exchanges[current].tx;
+        let end = (self.tx_position + buf.len()).min(recorded.len());
+        let matches =
+            end - self.tx_position == buf.len() && recorded[self.tx_position..end] == *buf;
+        if !matches && !self.tx_mismatch {
+            eprintln!(
+                "Replay: frame {} differs from the capture, recorded frame: {}",
+                current + 1,
+                to_hex(recorded)
+            );
+            self.tx_mismatch = true;
+        }
+        self.tx_position += buf.len();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Read for ReplayPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let recorded = match self.current {
+            Some(current) => &self.exchanges[current].rx[self.rx_position.get()..],
+            None => &[],
+        };
+        if recorded.is_empty() {
+            sleep(self.timeout);
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+
+        let count = recorded.len().min(buf.len());
+        buf[..count].copy_from_slice(&recorded[..count]);
+        self.rx_position.set(self.rx_position.get() + count);
+
+        Ok(count)
+    }
+}
+
+impl SerialPort for ReplayPort {
+    fn name(&self) -> Option<String> {
+        Some("replay".to_string())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(115200)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, _baud_rate: u32) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _data_bits: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _flow_control: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _parity: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _stop_bits: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        let available = match self.current {
+            Some(current) => self.exchanges[current].rx.len() - self.rx_position.get(),
+            None => 0,
+        };
+        Ok(available as u32)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        if let (Some(current), ClearBuffer::Input | ClearBuffer::All) =
+            (self.current, buffer_to_clear)
+        {
+            self.rx_position.set(self.exchanges[current].rx.len());
+        }
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Err(serialport::Error::new(
+            serialport::ErrorKind::Unknown,
+            "replay port can't be cloned",
+        ))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fakeport::{fake_session, test_settings};
+    use crate::protocol::{CMD_BL_GET_DEV_ID, CMD_BL_GET_VER};
+    use crate::session::Session;
+    use std::fs::{remove_file, write};
+    use std::io::Read;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("capture-test-{}-{name}", std::process::id()))
+    }
+
+    fn load_capture_from(name: &str, content: &str) -> Result<CaptureFile, String> {
+        let path = temp_path(name);
+        write(&path, content).unwrap();
+        let capture = load_capture(&path);
+        remove_file(&path).unwrap();
+        capture
+    }
+
+    #[test]
+    fn capture_file_is_parsed() {
+        let capture = load_capture_from(
+            "parsed",
+            "# stm32-flash-programmer-cli capture v1\n\
+             0.000160 TX 05 A1 EE 14 13 F5\n\
+             0.000447 RX BB 01\n\
+             0.000457 RX 10\n\
+             0.000470 CMD version\n\
+             \n\
+             0.000480 TX 05 A3 00 00 00 00\n\
+             0.000490 CMD dev_id\n",
+        )
+        .unwrap();
+
+        assert_eq!(capture.commands, ["version", "dev_id"]);
+        assert_eq!(capture.exchanges.len(), 2);
+        assert_eq!(
+            capture.exchanges[0].tx,
+            [0x05, 0xA1, 0xEE, 0x14, 0x13, 0xF5]
+        );
+        assert_eq!(capture.exchanges[0].rx, [0xBB, 0x01, 0x10]);
+        assert!(capture.exchanges[1].rx.is_empty());
+    }
+
+    #[test]
+    fn invalid_capture_lines_are_rejected() {
+        for (name, content) in [
+            ("rx-first", "0.000447 RX BB 01\n"),
+            ("bad-hex", "0.000160 TX 05 XY\n"),
+            ("unknown", "0.000160 FOO 05\n"),
+            ("no-kind", "0.000160\n"),
+        ] {
+            let error = load_capture_from(name, content).err().unwrap();
+            assert!(error.starts_with("Invalid capture file line 1"), "{error}");
+        }
+    }
+
+    #[test]
+    fn recorded_session_is_replayed() {
+        let path = temp_path("recorded");
+        let (mut session, _bootloader) = fake_session();
+        session.capture = Some(create_capture(&path).unwrap());
+        session.execute_command(&CMD_BL_GET_VER, &[], &[]).unwrap();
+        session.capture.as_mut().unwrap().command("version");
+        session
+            .execute_command(&CMD_BL_GET_DEV_ID, &[], &[])
+            .unwrap();
+        session.capture.as_mut().unwrap().command("dev_id");
+        drop(session);
+
+        let capture = load_capture(&path).unwrap();
+        remove_file(&path).unwrap();
+        assert_eq!(capture.commands, ["version", "dev_id"]);
+
+        let mut replay = Session::new(Box::new(ReplayPort::new(capture)), test_settings());
+        assert_eq!(
+            replay.execute_command(&CMD_BL_GET_VER, &[], &[]),
+            Ok(vec![0x10])
+        );
+        assert_eq!(
+            replay.execute_command(&CMD_BL_GET_DEV_ID, &[], &[]),
+            Ok(vec![0x21, 0x04])
+        );
+
+        // every recorded frame has been sent
+        let error = replay
+            .execute_command(&CMD_BL_GET_VER, &[], &[])
+            .unwrap_err();
+        assert!(error.starts_with("Failed to send the command"), "{error}");
+    }
+
+    #[test]
+    fn replay_times_out_when_the_recorded_reply_is_used_up() {
+        let capture = load_capture_from(
+            "timeout",
+            "0.000160 TX 05 A1 EE 14 13 F5\n0.000447 RX BB 01\n",
+        )
+        .unwrap();
+        let mut port = ReplayPort::new(capture);
+        port.set_timeout(Duration::from_millis(10)).unwrap();
+
+        let mut buffer = [0u8; 2];
+        let start = Instant::now();
+        assert_eq!(
+            port.read(&mut buffer).unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+        assert!(start.elapsed() >= Duration::from_millis(10));
+
+        port.write_all(&[0x05, 0xA1, 0xEE, 0x14, 0x13, 0xF5])
+            .unwrap();
+        assert_eq!(port.bytes_to_read().unwrap(), 2);
+        port.read_exact(&mut buffer).unwrap();
+        assert_eq!(buffer, [0xBB, 0x01]);
+        assert_eq!(
+            port.read(&mut buffer).unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+
+    #[test]
+    fn differing_frame_gets_the_recorded_reply() {
+        let capture = load_capture_from(
+            "differing",
+            "0.000160 TX 05 A1 EE 14 13 F5\n0.000447 RX BB 01 10\n",
+        )
+        .unwrap();
+        let mut port = ReplayPort::new(capture);
+
+        port.write_all(&[0x05, 0xA3, 0x00, 0x00, 0x00, 0x00])
+            .unwrap();
+        assert!(port.tx_mismatch);
+        let mut buffer = [0u8; 3];
+        port.read_exact(&mut buffer).unwrap();
+        assert_eq!(buffer, [0xBB, 0x01, 0x10]);
+    }
+
+    #[test]
+    fn reentry_timeout_is_replayed_with_the_recorded_probes() {
+        // waiting 1200 ms for the bootloader sent three probes that timed out
+        // after 200 ms each, followed by a version command that was answered
+        let capture = load_capture_from(
+            "reentry",
+            "0.000000 TX 05 A1 EE 14 13 F5\n\
+             0.700000 TX 05 A1 EE 14 13 F5\n\
+             1.400000 TX 05 A1 EE 14 13 F5\n\
+             1.600000 TX 05 A1 EE 14 13 F5\n\
+             1.600300 RX BB 01 10\n",
+        )
+        .unwrap();
+        let mut settings = test_settings();
+        settings.reentry_timeout = Duration::from_millis(1200);
+        let mut replay = Session::new(Box::new(ReplayPort::new(capture)), settings);
+        replay.mark_app_running();
+
+        let error = replay
+            .execute_command(&CMD_BL_GET_VER, &[], &[])
+            .unwrap_err();
+        assert!(error.contains("did not re-enter the bootloader"), "{error}");
+        assert_eq!(
+            replay.execute_command(&CMD_BL_GET_VER, &[], &[]),
+            Ok(vec![0x10])
+        );
+    }
+}
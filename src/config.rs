@@ -0,0 +1,41 @@
+use serde::Deserialize;
+use std::fs::read_to_string;
+use std::path::Path;
+
+use crate::bitcheck::BitCheck;
+use crate::device::ProgramUnit;
+use crate::imagecheck::ImageCheck;
+use crate::reset::ResetSettings;
+use crate::serial::SerialSettings;
+use crate::timeouts::TimeoutSettings;
+
+// config file used when no --config option is given, if it exists
+const DEFAULT_CONFIG_FILE: &str = "stm32-flash-programmer.toml";
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub reply_crc: bool,
+    pub fill_gaps: bool,
+    pub program_unit: Option<ProgramUnit>,
+    pub fix_alignment: bool,
+    pub bit_check: Option<BitCheck>,
+    pub image_check: Option<ImageCheck>,
+    pub reset: ResetSettings,
+    pub serial: SerialSettings,
+    pub timeouts: TimeoutSettings,
+}
+
+pub fn load_config(path: Option<&Path>) -> Result<Config, String> {
+    let path = match path {
+        Some(path) => path,
+        None if Path::new(DEFAULT_CONFIG_FILE).exists() => Path::new(DEFAULT_CONFIG_FILE),
+        None => return Ok(Config::default()),
+    };
+
+    let content = read_to_string(path)
+        .map_err(|error| format!("Failed to read config file '{}': {error}", path.display()))?;
+
+    toml::from_str(&content)
+        .map_err(|error| format!("Invalid config file '{}': {error}", path.display()))
+}
@@ -0,0 +1,139 @@
+use std::path::{Path, PathBuf};
+
+use crate::image::{load_image, parse_hex_u32, parse_hex_u8, save_image, ImageFormat};
+use crate::memory::MemoryImage;
+
+#[derive(clap::Args)]
+pub struct ConvertArgs {
+    /// Input images merged into the output, raw binaries are placed at
+    /// --base unless given as FILE@ADDRESS (hex)
+    #[arg(required = true, value_name = "INPUT")]
+    inputs: Vec<String>,
+
+    /// Output file
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Output format [default: from the output file extension]
+    #[arg(short, long, value_enum)]
+    format: Option<ImageFormat>,
+
+    /// Address of raw binary inputs (hex)
+    #[arg(long, value_parser = parse_hex_u32, default_value = "0x08008000")]
+    base: u32,
+
+    /// Byte filling the gaps between segments of raw binary outputs and the
+    /// alignment padding (hex)
+    #[arg(long, value_parser = parse_hex_u8, default_value = "0xFF")]
+    fill: u8,
+
+    /// Pad the start and end of every segment to a multiple of this many bytes
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u32).range(1..))]
+    align: Option<u32>,
+
+    /// Write every segment to its own file, named after the output file
+    /// with the segment address appended
+    #[arg(long)]
+    split: bool,
+}
+
+// Splits "FILE@ADDRESS" into the file and the address, if given
+fn parse_input(input: &str) -> Result<(&Path, Option<u32>), String> {
+    match input.rsplit_once('@') {
+        Some((file, address)) => Ok((Path::new(file), Some(parse_hex_u32(address)?))),
+        None => Ok((Path::new(input), None)),
+    }
+}
+
+fn split_file_name(output: &Path, address: u32) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match output.extension() {
+        Some(extension) => format!("{stem}_{address:08X}.{}", extension.to_string_lossy()),
+        None => format!("{stem}_{address:08X}"),
+    };
+    output.with_file_name(name)
+}
+
+// Loads and merges the input images and writes them in the output format.
+// Returns true when every file was converted.
+pub fn convert_images(args: &ConvertArgs) -> bool {
+    let mut image = MemoryImage::new();
+
+    for input in &args.inputs {
+        let (path, address) = match parse_input(input) {
+            Ok(input) => input,
+            Err(error) => {
+                eprintln!("{error}");
+                return false;
+            }
+        };
+
+        let format = ImageFormat::from_path(path);
+        if address.is_some() && format != ImageFormat::Bin {
+            eprintln!(
+                "An address can only be given for raw binaries, '{}' is {}",
+                path.display(),
+                format.name()
+            );
+            return false;
+        }
+
+        let base = address.unwrap_or(args.base);
+        let result = load_image(path, format, base).and_then(|input| image.merge(input));
+        if let Err(error) = result {
+            eprintln!("{error}");
+            return false;
+        }
+    }
+
+    if image.is_empty() {
+        eprintln!("The input images contain no data!");
+        return false;
+    }
+    if let Some(alignment) = args.align {
+        image = match image.align(alignment, args.fill) {
+            Ok(image) => image,
+            Err(error) => {
+                eprintln!("{error}");
+                return false;
+            }
+        };
+    }
+
+    let format = args
+        .format
+        .unwrap_or_else(|| ImageFormat::from_path(&args.output));
+
+    let outputs: Vec<(PathBuf, MemoryImage)> = if args.split {
+        image
+            .segments()
+            .iter()
+            .map(|segment| {
+                let mut output = MemoryImage::new();
+                // a single segment never overlaps
+                let _ = output.add_segment(segment.address, segment.data.clone());
+                (split_file_name(&args.output, segment.address), output)
+            })
+            .collect()
+    } else {
+        vec![(args.output.clone(), image)]
+    };
+
+    for (path, image) in outputs {
+        if let Err(error) = save_image(&path, format, &image, args.fill) {
+            eprintln!("{error}");
+            return false;
+        }
+
+        println!(
+            "Wrote {} ({}): {} segments, {} bytes from 0x{:08X}",
+            path.display(),
+            format.name(),
+            image.segments().len(),
+            image.size(),
+            image.start().unwrap_or_default()
+        );
+    }
+
+    true
+}
@@ -0,0 +1,200 @@
+// Memory layout of the STM32F446xE the bootloader runs on
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+pub struct FlashSector {
+    pub start: u32,
+    pub size: u32,
+}
+
+pub const FLASH_SECTORS: [FlashSector; 8] = [
+    FlashSector {
+        start: 0x08000000,
+        size: 16 * 1024,
+    },
+    FlashSector {
+        start: 0x08004000,
+        size: 16 * 1024,
+    },
+    FlashSector {
+        start: 0x08008000,
+        size: 16 * 1024,
+    },
+    FlashSector {
+        start: 0x0800C000,
+        size: 16 * 1024,
+    },
+    FlashSector {
+        start: 0x08010000,
+        size: 64 * 1024,
+    },
+    FlashSector {
+        start: 0x08020000,
+        size: 128 * 1024,
+    },
+    FlashSector {
+        start: 0x08040000,
+        size: 128 * 1024,
+    },
+    FlashSector {
+        start: 0x08060000,
+        size: 128 * 1024,
+    },
+];
+
+pub fn is_flash_mem_address(addr: &u32) -> bool {
+    let last_sector = &FLASH_SECTORS[FLASH_SECTORS.len() - 1];
+    (FLASH_SECTORS[0].start..last_sector.start + last_sector.size).contains(addr)
+}
+
+// Checks that all of [start, start + size) lies in flash, the error shows how
+// far the range runs outside of it
+pub fn check_flash_range(start: u32, size: usize) -> Result<(), String> {
+    let last_sector = &FLASH_SECTORS[FLASH_SECTORS.len() - 1];
+    let flash_start = FLASH_SECTORS[0].start as u64;
+    let flash_end = (last_sector.start + last_sector.size) as u64;
+    let end = (start as u64).saturating_add(size as u64);
+    let range = format!(
+        "0x{start:08X} - 0x{:08X} ({size} bytes)",
+        end.max(start as u64 + 1) - 1
+    );
+
+    if !is_flash_mem_address(&start) {
+        return Err(format!(
+            "{range} starts outside of FLASH memory (0x{flash_start:08X} - 0x{:08X})!",
+            flash_end - 1
+        ));
+    }
+    if end > flash_end {
+        let overflow = end - flash_end;
+        return Err(format!(
+            "{range} runs {overflow} bytes (0x{flash_end:08X} - 0x{:08X}) past the end of FLASH memory at 0x{:08X}!",
+            end - 1,
+            flash_end - 1
+        ));
+    }
+    Ok(())
+}
+
+// Returns the number of the flash sector containing the address
+pub fn sector_index(addr: u32) -> Option<usize> {
+    FLASH_SECTORS
+        .iter()
+        .position(|sector| (sector.start..sector.start + sector.size).contains(&addr))
+}
+
+// SRAM1 and SRAM2 are contiguous
+pub const SRAM_START: u32 = 0x20000000;
+pub const SRAM_SIZE: u32 = 128 * 1024;
+
+// start of the application, the bootloader itself occupies sectors 0 and 1
+pub const APP_BASE_ADDRESS: u32 = 0x08008000;
+
+// VTOR ignores the low 9 bits, the 112 vectors of the STM32F446 rounded up to
+// a power of two
+pub const VECTOR_TABLE_ALIGNMENT: u32 = 512;
+
+// Checks the first two vector table entries of an application: the initial
+// stack pointer has to point into SRAM (the end of SRAM included since the
+// stack grows down) and the reset handler has to be a Thumb address in flash
+pub fn check_vector_table(initial_sp: u32, reset_handler: u32) -> Result<(), String> {
+    if !(SRAM_START + 1..=SRAM_START + SRAM_SIZE).contains(&initial_sp)
+        || !initial_sp.is_multiple_of(4)
+    {
+        return Err(format!(
+            "Initial stack pointer 0x{initial_sp:08X} does not point into SRAM!"
+        ));
+    }
+    if reset_handler & 1 == 0 {
+        return Err(format!(
+            "Reset handler 0x{reset_handler:08X} does not have the Thumb bit set!"
+        ));
+    }
+    if !is_flash_mem_address(&(reset_handler & !1)) {
+        return Err(format!(
+            "Reset handler 0x{reset_handler:08X} is outside of FLASH memory!"
+        ));
+    }
+    Ok(())
+}
+
+// Number of bytes programmed at once, set by the flash parallelism (PSIZE)
+// the bootloader uses
+#[derive(Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProgramUnit {
+    Byte,
+    HalfWord,
+    Word,
+    DoubleWord,
+}
+
+impl ProgramUnit {
+    pub fn size(&self) -> u32 {
+        match self {
+            ProgramUnit::Byte => 1,
+            ProgramUnit::HalfWord => 2,
+            ProgramUnit::Word => 4,
+            ProgramUnit::DoubleWord => 8,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_vector_table_is_accepted() {
+        assert!(check_vector_table(0x20020000, 0x080081C5).is_ok());
+        assert!(check_vector_table(0x20000004, 0x08000001).is_ok());
+        assert!(check_vector_table(0x2001FFF0, 0x0807FFFF).is_ok());
+    }
+
+    #[test]
+    fn stack_pointer_outside_of_sram_is_rejected() {
+        for initial_sp in [0x20000000, 0x20020004, 0x20010002, 0xFFFFFFFF, 0x08008000] {
+            let error = check_vector_table(initial_sp, 0x080081C5).unwrap_err();
+            assert!(error.contains("does not point into SRAM"), "{error}");
+        }
+    }
+
+    #[test]
+    fn reset_handler_has_to_be_thumb_code_in_flash() {
+        let error = check_vector_table(0x20020000, 0x080081C4).unwrap_err();
+        assert!(error.contains("Thumb bit"), "{error}");
+
+        for reset_handler in [0x07FFFFFF, 0x08080001, 0x20000001, 0xFFFFFFFF] {
+            let error = check_vector_table(0x20020000, reset_handler).unwrap_err();
+            assert!(error.contains("outside of FLASH"), "{error}");
+        }
+    }
+
+    #[test]
+    fn ranges_inside_of_flash_are_accepted() {
+        assert!(check_flash_range(0x0800_0000, 512 * 1024).is_ok());
+        assert!(check_flash_range(0x0807_F000, 4096).is_ok());
+        assert!(check_flash_range(0x0807_FFFF, 1).is_ok());
+    }
+
+    #[test]
+    fn ranges_past_the_end_of_flash_show_the_overflow() {
+        let error = check_flash_range(0x0807_F000, 8192).unwrap_err();
+        assert_eq!(
+            error,
+            "0x0807F000 - 0x08080FFF (8192 bytes) runs 4096 bytes (0x08080000 - 0x08080FFF) \
+             past the end of FLASH memory at 0x0807FFFF!"
+        );
+        assert!(check_flash_range(0x0807_FFFF, 2).is_err());
+        assert!(check_flash_range(0x0807_FFFF, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn ranges_starting_outside_of_flash_are_rejected() {
+        for start in [0x07FF_FFFF, 0x0808_0000, 0x2000_0000] {
+            let error = check_flash_range(start, 4).unwrap_err();
+            assert!(error.contains("starts outside of FLASH"), "{error}");
+        }
+    }
+}
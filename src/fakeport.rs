@@ -0,0 +1,300 @@
+// Serial port emulating the bootloader for the tests, the frames written to
+// it are answered the way the bootloader does with the flash kept in memory
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::bitcheck::BitCheck;
+use crate::device::FLASH_SECTORS;
+use crate::imagecheck::ImageCheck;
+use crate::protocol::{
+    get_crc, CMD_BL_FLASH_ERASE, CMD_BL_GET_DEV_ID, CMD_BL_GET_HELP, CMD_BL_GET_RDP_LEVEL,
+    CMD_BL_GET_RW_PROTECT, CMD_BL_GET_VER, CMD_BL_JMP_ADDR, CMD_BL_MEM_READ, CMD_BL_MEM_WRITE,
+    CMD_BL_SET_RW_PROTECT,
+};
+use crate::reset::{parse_sequences, ResetSettings};
+use crate::session::{Session, Settings, DEFAULT_REENTRY_TIMEOUT};
+use crate::timeouts::{TimeoutSettings, Timeouts};
+
+const FLASH_START: u32 = 0x08000000;
+const FLASH_SIZE: usize = 512 * 1024;
+
+pub struct Bootloader {
+    pub flash: Vec<u8>,
+    // append a CRC to the replies, and send a wrong one
+    pub reply_crc: bool,
+    pub corrupt_crc: bool,
+    // sent instead of the emulated reply to the next frames
+    pub replies: VecDeque<Vec<u8>>,
+    // every frame received, without the CRC
+    pub frames: Vec<Vec<u8>>,
+}
+
+pub struct FakePort {
+    bootloader: Arc<Mutex<Bootloader>>,
+    frame: Vec<u8>,
+    rx: Mutex<VecDeque<u8>>,
+    timeout: Duration,
+}
+
+pub fn test_settings() -> Settings {
+    Settings {
+        reply_crc: false,
+        fill_gaps: false,
+        program_unit: 1,
+        fix_alignment: false,
+        bit_check: BitCheck::Abort,
+        image_check: ImageCheck::Off,
+        timeouts: Timeouts::new(Duration::from_secs(2), TimeoutSettings::default()),
+        reentry_timeout: DEFAULT_REENTRY_TIMEOUT,
+        reset: parse_sequences(&ResetSettings::default()).unwrap(),
+        monitor: None,
+        trace: None,
+    }
+}
+
+// Returns a session talking to an emulated bootloader with erased flash and
+// the bootloader to inspect the frames and the flash
+pub fn fake_session() -> (Session, Arc<Mutex<Bootloader>>) {
+    let bootloader = Arc::new(Mutex::new(Bootloader {
+        flash: vec![0xFF; FLASH_SIZE],
+        reply_crc: false,
+        corrupt_crc: false,
+        replies: VecDeque::new(),
+        frames: Vec::new(),
+    }));
+    let port = FakePort {
+        bootloader: bootloader.clone(),
+        frame: Vec::new(),
+        rx: Mutex::new(VecDeque::new()),
+        timeout: Duration::from_secs(2),
+    };
+    (Session::new(Box::new(port), test_settings()), bootloader)
+}
+
+impl Bootloader {
+    // Returns the flash offset of [address, address + length) if it lies in
+    // flash
+    fn flash_range(&self, address: u32, length: usize) -> Option<std::ops::Range<usize>> {
+        let start = address.checked_sub(FLASH_START)? as usize;
+        (start + length <= self.flash.len()).then_some(start..start + length)
+    }
+
+    fn handle(&mut self, code: u8, args: &[u8]) -> Vec<u8> {
+        let address = |args: &[u8]| u32::from_le_bytes(args[0..4].try_into().unwrap());
+
+        if code == CMD_BL_GET_VER.code {
+            vec![0x10]
+        } else if code == CMD_BL_GET_HELP.code {
+            vec![0xA1, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9, 0xAA]
+        } else if code == CMD_BL_GET_DEV_ID.code {
+            vec![0x21, 0x04]
+        } else if code == CMD_BL_GET_RDP_LEVEL.code {
+            vec![0xAA]
+        } else if code == CMD_BL_JMP_ADDR.code {
+            vec![0]
+        } else if code == CMD_BL_FLASH_ERASE.code {
+            let (first, count) = (args[0] as usize, args[1] as usize);
+            if first + count > FLASH_SECTORS.len() {
+                return vec![1];
+            }
+            for sector in &FLASH_SECTORS[first..first + count] {
+                let range = self
+                    .flash_range(sector.start, sector.size as usize)
+                    .unwrap();
+                self.flash[range].fill(0xFF);
+            }
+            vec![0]
+        } else if code == CMD_BL_MEM_WRITE.code {
+            let data = &args[5..5 + args[4] as usize];
+            match self.flash_range(address(args), data.len()) {
+                Some(range) => {
+                    // programming only clears bits
+                    for (byte, new) in self.flash[range].iter_mut().zip(data) {
+                        *byte &= new;
+                    }
+                    vec![1]
+                }
+                None => vec![0],
+            }
+        } else if code == CMD_BL_MEM_READ.code {
+            match self.flash_range(address(args), args[4] as usize) {
+                Some(range) => [&[1], &self.flash[range]].concat(),
+                None => vec![0],
+            }
+        } else if code == CMD_BL_SET_RW_PROTECT.code {
+            vec![1]
+        } else if code == CMD_BL_GET_RW_PROTECT.code {
+            vec![0; FLASH_SECTORS.len()]
+        } else {
+            vec![]
+        }
+    }
+
+    fn reply(&mut self, frame: &[u8]) -> Vec<u8> {
+        let body = &frame[..frame.len() - 4];
+        let crc = u32::from_le_bytes(frame[frame.len() - 4..].try_into().unwrap());
+        self.frames.push(body.to_vec());
+        if get_crc(body) != crc {
+            return vec![0xEE, 0];
+        }
+
+        let payload = match self.replies.pop_front() {
+            Some(payload) => payload,
+            None => self.handle(body[1], &body[2..]),
+        };
+        let mut reply = vec![0xBB, payload.len() as u8];
+        reply.extend(&payload);
+        if self.reply_crc {
+            let mut crc = get_crc(&reply[1..]);
+            if self.corrupt_crc {
+                crc ^= 1;
+            }
+            reply.extend(crc.to_le_bytes());
+        }
+        reply
+    }
+}
+
+impl io::Write for FakePort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.frame.extend(buf);
+        // the first byte is the length of the rest of the frame
+        if self.frame.len() > 1 && self.frame.len() == self.frame[0] as usize + 1 {
+            let reply = self.bootloader.lock().unwrap().reply(&self.frame);
+            self.rx.lock().unwrap().extend(reply);
+            self.frame.clear();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Read for FakePort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut rx = self.rx.lock().unwrap();
+        if rx.is_empty() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        let count = rx.len().min(buf.len());
+        for (byte, received) in buf.iter_mut().zip(rx.drain(..count)) {
+            *byte = received;
+        }
+        Ok(count)
+    }
+}
+
+impl SerialPort for FakePort {
+    fn name(&self) -> Option<String> {
+        Some("fake".to_string())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(115200)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, _baud_rate: u32) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _data_bits: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _flow_control: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _parity: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _stop_bits: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(self.rx.lock().unwrap().len() as u32)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        if let ClearBuffer::Input | ClearBuffer::All = buffer_to_clear {
+            self.rx.lock().unwrap().clear();
+        }
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Err(serialport::Error::new(
+            serialport::ErrorKind::Unknown,
+            "fake port can't be cloned",
+        ))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
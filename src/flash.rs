@@ -0,0 +1,174 @@
+use crate::bitcheck::check_bit_transitions;
+use crate::device::check_flash_range;
+use crate::memory::MemoryImage;
+use crate::session::{Session, Settings};
+
+// Pads the image with 0xFF to the programming unit, which leaves the padded
+// flash bytes unchanged. Misaligned start addresses are an error unless
+// --fix-alignment is given.
+fn align_to_program_unit(settings: &Settings, image: &MemoryImage) -> Result<MemoryImage, String> {
+    let unit = settings.program_unit;
+    if unit == 1 {
+        return Ok(image.clone());
+    }
+
+    let aligned = image.align(unit, 0xFF)?;
+    for segment in image.segments() {
+        let start = segment.address - segment.address % unit;
+        let end = segment.end().next_multiple_of(unit);
+        if start == segment.address && end == segment.end() {
+            continue;
+        }
+
+        if start != segment.address && !settings.fix_alignment {
+            return Err(format!(
+                "Write address 0x{:08X} is not aligned to the {unit}-byte programming unit! \
+                 Use --fix-alignment to pad it with 0xFF.",
+                segment.address
+            ));
+        }
+        println!(
+            "Padding 0x{:08X} - 0x{:08X} to 0x{start:08X} - 0x{:08X} with 0xFF \
+             ({} bytes before, {} bytes after) for the {unit}-byte programming unit",
+            segment.address,
+            segment.end() - 1,
+            end - 1,
+            segment.address - start,
+            end - segment.end()
+        );
+    }
+
+    Ok(aligned)
+}
+
+pub fn write_image(session: &mut Session, image: &MemoryImage) -> Result<(), String> {
+    // scripts write without going through load_command_image
+    for segment in image.segments() {
+        check_flash_range(segment.address, segment.data.len())?;
+    }
+    let mut aligned = align_to_program_unit(&session.settings, image)?;
+    // the 0xFF padding never changes the flash, only the image is checked
+    for (start, end) in check_bit_transitions(session, image)? {
+        aligned = aligned.without(start, end);
+    }
+    for segment in aligned.segments() {
+        session.write_memory(segment.address, &segment.data)?;
+    }
+    Ok(())
+}
+
+// Reads back every segment of the image and compares it, the error lists the
+// first differing bytes
+pub fn verify_image(session: &mut Session, image: &MemoryImage) -> Result<(), String> {
+    const MAX_REPORTED_MISMATCHES: usize = 10;
+
+    let mut mismatches = Vec::new();
+    for segment in image.segments() {
+        let memory = session.read_memory(segment.address, segment.data.len())?;
+        for (offset, (expected, actual)) in segment.data.iter().zip(&memory).enumerate() {
+            if expected != actual {
+                mismatches.push((segment.address + offset as u32, *expected, *actual));
+            }
+        }
+    }
+
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+
+    let mut error = format!("Verify: FAILURE ({} bytes differ)", mismatches.len());
+    for (address, expected, actual) in mismatches.iter().take(MAX_REPORTED_MISMATCHES) {
+        error += &format!("\n  0x{address:08X}: expected 0x{expected:02X}, read 0x{actual:02X}");
+    }
+    if mismatches.len() > MAX_REPORTED_MISMATCHES {
+        error += "\n  ...";
+    }
+    Err(error)
+}
+
+// Reads the initial stack pointer and the reset handler address, the first
+// two entries of the vector table at the given address
+pub fn read_vector_table(session: &mut Session, base_address: u32) -> Result<(u32, u32), String> {
+    check_flash_range(base_address, 8)?;
+    let vectors = session.read_memory(base_address, 8)?;
+    Ok((
+        u32::from_le_bytes(vectors[0..4].try_into().unwrap()),
+        u32::from_le_bytes(vectors[4..8].try_into().unwrap()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fakeport::{fake_session, test_settings};
+
+    fn settings(program_unit: u32, fix_alignment: bool) -> Settings {
+        Settings {
+            program_unit,
+            fix_alignment,
+            ..test_settings()
+        }
+    }
+
+    fn image(address: u32, data: &[u8]) -> MemoryImage {
+        let mut image = MemoryImage::new();
+        image.add_segment(address, data.to_vec()).unwrap();
+        image
+    }
+
+    fn layout(image: &MemoryImage) -> Vec<(u32, Vec<u8>)> {
+        image
+            .segments()
+            .iter()
+            .map(|segment| (segment.address, segment.data.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn byte_unit_leaves_the_image_unchanged() {
+        let image = image(0x0800_8001, &[1, 2, 3]);
+        let aligned = align_to_program_unit(&settings(1, false), &image).unwrap();
+        assert_eq!(layout(&aligned), layout(&image));
+    }
+
+    #[test]
+    fn end_is_padded_with_erased_bytes() {
+        let image = image(0x0800_8000, &[1, 2, 3]);
+        let aligned = align_to_program_unit(&settings(4, false), &image).unwrap();
+        assert_eq!(layout(&aligned), vec![(0x0800_8000, vec![1, 2, 3, 0xFF])]);
+    }
+
+    #[test]
+    fn misaligned_start_needs_fix_alignment() {
+        let image = image(0x0800_8001, &[1, 2, 3]);
+        let error = align_to_program_unit(&settings(8, false), &image)
+            .err()
+            .unwrap();
+        assert!(error.contains("--fix-alignment"), "{error}");
+
+        let aligned = align_to_program_unit(&settings(8, true), &image).unwrap();
+        assert_eq!(
+            layout(&aligned),
+            vec![(0x0800_8000, vec![0xFF, 1, 2, 3, 0xFF, 0xFF, 0xFF, 0xFF])]
+        );
+    }
+
+    #[test]
+    fn vector_table_is_read_from_flash() {
+        let (mut session, bootloader) = fake_session();
+        bootloader.lock().unwrap().flash[0x8000..0x8008]
+            .copy_from_slice(&[0x00, 0x00, 0x02, 0x20, 0xC5, 0x81, 0x00, 0x08]);
+        assert_eq!(
+            read_vector_table(&mut session, 0x0800_8000),
+            Ok((0x2002_0000, 0x0800_81C5))
+        );
+    }
+
+    #[test]
+    fn vector_table_past_the_end_of_flash_is_not_read() {
+        let (mut session, bootloader) = fake_session();
+        let error = read_vector_table(&mut session, 0x0807_FFFC).unwrap_err();
+        assert!(error.contains("past the end of FLASH"), "{error}");
+        assert!(bootloader.lock().unwrap().frames.is_empty());
+    }
+}
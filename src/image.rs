@@ -0,0 +1,737 @@
+use clap::ValueEnum;
+use std::fmt::Write;
+use std::fs::{read, write};
+use std::io::{stdin, Read};
+use std::path::Path;
+
+use crate::memory::{MemoryImage, Segment};
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum ImageFormat {
+    Bin,
+    #[value(name = "ihex")]
+    IntelHex,
+    Srec,
+    Elf,
+}
+
+impl ImageFormat {
+    pub fn from_path(path: &Path) -> ImageFormat {
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("hex" | "ihex" | "ihx") => ImageFormat::IntelHex,
+            Some("srec" | "s19" | "s28" | "s37" | "mot") => ImageFormat::Srec,
+            Some("elf" | "axf" | "out") => ImageFormat::Elf,
+            _ => ImageFormat::Bin,
+        }
+    }
+
+    // Guesses the format of data without a file name, e.g. piped into stdin
+    pub fn from_content(content: &[u8]) -> ImageFormat {
+        if content.starts_with(b"\x7FELF") {
+            return ImageFormat::Elf;
+        }
+        let is_text = content
+            .iter()
+            .all(|byte| byte.is_ascii_graphic() || byte.is_ascii_whitespace());
+        let start = content
+            .iter()
+            .position(|byte| !byte.is_ascii_whitespace())
+            .unwrap_or(content.len());
+        match &content[start..] {
+            [b':', digit, ..] if is_text && digit.is_ascii_hexdigit() => ImageFormat::IntelHex,
+            [b'S', digit, ..] if is_text && digit.is_ascii_digit() => ImageFormat::Srec,
+            _ => ImageFormat::Bin,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImageFormat::Bin => "raw binary",
+            ImageFormat::IntelHex => "Intel HEX",
+            ImageFormat::Srec => "Motorola S-record",
+            ImageFormat::Elf => "ELF",
+        }
+    }
+}
+
+pub fn parse_hex_u32(input: &str) -> Result<u32, String> {
+    let input_lowercase = input.to_lowercase();
+    u32::from_str_radix(input_lowercase.trim_start_matches("0x"), 16)
+        .map_err(|_| format!("Invalid hex number '{input}'"))
+}
+
+pub fn parse_hex_u8(input: &str) -> Result<u8, String> {
+    let input_lowercase = input.to_lowercase();
+    u8::from_str_radix(input_lowercase.trim_start_matches("0x"), 16)
+        .map_err(|_| format!("Invalid hex byte '{input}'"))
+}
+
+// Byte counts and offsets are decimal, or hex with a 0x prefix
+pub fn parse_size(input: &str) -> Result<usize, String> {
+    let input_lowercase = input.to_lowercase();
+    let result = match input_lowercase.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => input_lowercase.parse(),
+    };
+    result.map_err(|_| format!("Invalid number '{input}'"))
+}
+
+// Loads a firmware image, raw binaries are placed at the given base address
+pub fn load_image(path: &Path, format: ImageFormat, base: u32) -> Result<MemoryImage, String> {
+    let content = read_image_file(path)?;
+    parse_image(path, content, format, base)
+}
+
+// Reads the whole file, "-" reads stdin until it is closed
+pub fn read_image_file(path: &Path) -> Result<Vec<u8>, String> {
+    if path == Path::new("-") {
+        let mut content = Vec::new();
+        stdin()
+            .read_to_end(&mut content)
+            .map_err(|error| format!("Failed to read stdin: {error}"))?;
+        return Ok(content);
+    }
+    read(path).map_err(|error| format!("Failed to read file '{}': {error}", path.display()))
+}
+
+pub fn parse_image(
+    path: &Path,
+    content: Vec<u8>,
+    format: ImageFormat,
+    base: u32,
+) -> Result<MemoryImage, String> {
+    let segments = match format {
+        ImageFormat::Bin => vec![Segment {
+            address: base,
+            data: content,
+        }],
+        ImageFormat::IntelHex => parse_intel_hex(&content)?,
+        ImageFormat::Srec => parse_srec(&content)?,
+        ImageFormat::Elf => parse_elf(&content)?,
+    };
+
+    MemoryImage::from_segments(segments)
+        .map_err(|error| format!("Invalid image '{}': {error}", path.display()))
+}
+
+fn parse_intel_hex(content: &[u8]) -> Result<Vec<Segment>, String> {
+    let text = String::from_utf8_lossy(content);
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut upper_address = 0u32;
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let invalid_record =
+            |reason: &str| format!("Invalid Intel HEX record on line {}: {reason}", index + 1);
+
+        if !line.is_ascii() {
+            return Err(invalid_record("invalid character"));
+        }
+        let Some(hex) = line.strip_prefix(':') else {
+            return Err(invalid_record("missing ':'"));
+        };
+        if hex.len() % 2 != 0 {
+            return Err(invalid_record("odd number of digits"));
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid_record("invalid hex digit"))?;
+
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(invalid_record("wrong length"));
+        }
+        if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(invalid_record("wrong checksum"));
+        }
+
+        let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let data = &bytes[4..bytes.len() - 1];
+
+        match bytes[3] {
+            // data
+            0x00 => {
+                let address = upper_address + offset;
+                if address.checked_add(data.len() as u32).is_none() {
+                    return Err(invalid_record("data past the end of the address space"));
+                }
+                match segments.last_mut() {
+                    Some(segment) if segment.end() == address => {
+                        segment.data.extend_from_slice(data)
+                    }
+                    _ => segments.push(Segment {
+                        address,
+                        data: data.to_vec(),
+                    }),
+                }
+            }
+            // end of file
+            0x01 => break,
+            // extended segment address
+            0x02 if data.len() == 2 => {
+                upper_address = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4
+            }
+            // extended linear address
+            0x04 if data.len() == 2 => {
+                upper_address = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16
+            }
+            // start segment and start linear address, not needed for flashing
+            0x03 | 0x05 => {}
+            _ => return Err(invalid_record("unsupported record type")),
+        }
+    }
+
+    Ok(segments)
+}
+
+fn parse_srec(content: &[u8]) -> Result<Vec<Segment>, String> {
+    let text = String::from_utf8_lossy(content);
+    let mut segments: Vec<Segment> = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let invalid_record =
+            |reason: &str| format!("Invalid S-record on line {}: {reason}", index + 1);
+
+        if !line.is_ascii() {
+            return Err(invalid_record("invalid character"));
+        }
+        let Some(hex) = line.strip_prefix('S') else {
+            return Err(invalid_record("missing 'S'"));
+        };
+        let Some(record_type) = hex.chars().next() else {
+            return Err(invalid_record("missing record type"));
+        };
+        let hex = &hex[1..];
+        if hex.len() % 2 != 0 {
+            return Err(invalid_record("odd number of digits"));
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid_record("invalid hex digit"))?;
+
+        if bytes.len() < 2 || bytes.len() != bytes[0] as usize + 1 {
+            return Err(invalid_record("wrong length"));
+        }
+        if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0xFF {
+            return Err(invalid_record("wrong checksum"));
+        }
+
+        let address_size = match record_type {
+            '1' => 2,
+            '2' => 3,
+            '3' => 4,
+            // header, record count and start address, not needed for flashing
+            '0' | '5' | '6' | '7' | '8' | '9' => continue,
+            _ => return Err(invalid_record("unsupported record type")),
+        };
+        if bytes.len() < address_size + 2 {
+            return Err(invalid_record("wrong length"));
+        }
+
+        let address = bytes[1..=address_size]
+            .iter()
+            .fold(0u32, |address, byte| address << 8 | *byte as u32);
+        let data = &bytes[address_size + 1..bytes.len() - 1];
+        if address.checked_add(data.len() as u32).is_none() {
+            return Err(invalid_record("data past the end of the address space"));
+        }
+
+        match segments.last_mut() {
+            Some(segment) if segment.end() == address => segment.data.extend_from_slice(data),
+            _ => segments.push(Segment {
+                address,
+                data: data.to_vec(),
+            }),
+        }
+    }
+
+    Ok(segments)
+}
+
+// Loads the PT_LOAD program headers of a 32-bit little endian ELF file at
+// their physical (load) addresses
+fn parse_elf(content: &[u8]) -> Result<Vec<Segment>, String> {
+    const PT_LOAD: u32 = 1;
+
+    if content.len() < 52 || &content[0..4] != b"\x7FELF" {
+        return Err("Not an ELF file!".to_string());
+    }
+    if content[4] != 1 || content[5] != 1 {
+        return Err("Only 32-bit little endian ELF files are supported!".to_string());
+    }
+
+    let u16_at = |offset: usize| u16::from_le_bytes([content[offset], content[offset + 1]]);
+    let u32_at =
+        |offset: usize| u32::from_le_bytes(content[offset..offset + 4].try_into().unwrap());
+
+    let ph_offset = u32_at(0x1C) as usize;
+    let ph_size = u16_at(0x2A) as usize;
+    let ph_count = u16_at(0x2C) as usize;
+    if ph_size < 32 || ph_offset + ph_size * ph_count > content.len() {
+        return Err("Invalid ELF program header table!".to_string());
+    }
+
+    let mut segments = Vec::new();
+    for index in 0..ph_count {
+        let header = ph_offset + index * ph_size;
+        if u32_at(header) != PT_LOAD {
+            continue;
+        }
+
+        let offset = u32_at(header + 4) as usize;
+        let address = u32_at(header + 12);
+        let size = u32_at(header + 16) as usize;
+        if offset + size > content.len() {
+            return Err(format!(
+                "ELF segment {index} extends past the end of the file!"
+            ));
+        }
+
+        segments.push(Segment {
+            address,
+            data: content[offset..offset + size].to_vec(),
+        });
+    }
+
+    Ok(segments)
+}
+
+// Writes the image in the given format, the gaps of raw binaries are
+// filled with the fill byte
+pub fn save_image(
+    path: &Path,
+    format: ImageFormat,
+    image: &MemoryImage,
+    fill: u8,
+) -> Result<(), String> {
+    let content = match format {
+        ImageFormat::Bin => to_bin(image, fill)?,
+        ImageFormat::IntelHex => to_intel_hex(image.segments()).into_bytes(),
+        ImageFormat::Srec => to_srec(image.segments()).into_bytes(),
+        ImageFormat::Elf => to_elf(image.segments()),
+    };
+
+    write(path, content)
+        .map_err(|error| format!("Failed to write file '{}': {error}", path.display()))
+}
+
+// largest number of fill bytes written between the segments of a raw binary,
+// images spread further apart, e.g. flash and RAM, need another format
+const MAX_BIN_GAP_FILL: usize = 16 * 1024 * 1024;
+
+fn to_bin(image: &MemoryImage, fill: u8) -> Result<Vec<u8>, String> {
+    let (Some(start), Some(end)) = (image.start(), image.end()) else {
+        return Ok(Vec::new());
+    };
+    let gap_fill = (end - start) as usize - image.size();
+    if gap_fill > MAX_BIN_GAP_FILL {
+        return Err(format!(
+            "A raw binary of 0x{start:08X} - 0x{:08X} would contain {gap_fill} fill bytes \
+             between the segments! Use --split or Intel HEX, S-record or ELF output instead.",
+            end - 1
+        ));
+    }
+
+    Ok(image.fill_gaps(fill).segments()[0].data.clone())
+}
+
+// number of data bytes in each Intel HEX and S-record line
+const RECORD_DATA_SIZE: usize = 16;
+
+fn hex_record(bytes: &[u8], checksum: u8) -> String {
+    let mut record = String::new();
+    for byte in bytes {
+        let _ = write!(record, "{byte:02X}");
+    }
+    let _ = writeln!(record, "{checksum:02X}");
+    record
+}
+
+fn to_intel_hex(segments: &[Segment]) -> String {
+    let intel_hex_record = |record_type: u8, offset: u16, data: &[u8]| {
+        let mut bytes = vec![data.len() as u8];
+        bytes.extend_from_slice(&offset.to_be_bytes());
+        bytes.push(record_type);
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        format!(":{}", hex_record(&bytes, sum.wrapping_neg()))
+    };
+
+    let mut output = String::new();
+    let mut upper_address = None;
+    for segment in segments {
+        let mut address = segment.address;
+        let mut data = segment.data.as_slice();
+        while !data.is_empty() {
+            let upper = (address >> 16) as u16;
+            if upper_address != Some(upper) {
+                output += &intel_hex_record(0x04, 0, &upper.to_be_bytes());
+                upper_address = Some(upper);
+            }
+
+            // data records don't cross 64K boundaries
+            let to_boundary = 0x10000 - (address & 0xFFFF) as usize;
+            let size = data.len().min(RECORD_DATA_SIZE).min(to_boundary);
+            output += &intel_hex_record(0x00, address as u16, &data[..size]);
+            address += size as u32;
+            data = &data[size..];
+        }
+    }
+    output += &intel_hex_record(0x01, 0, &[]);
+
+    output
+}
+
+fn to_srec(segments: &[Segment]) -> String {
+    let srec_record = |record_type: char, address: &[u8], data: &[u8]| {
+        let mut bytes = vec![(address.len() + data.len() + 1) as u8];
+        bytes.extend_from_slice(address);
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        format!("S{record_type}{}", hex_record(&bytes, !sum))
+    };
+
+    let mut output = srec_record('0', &[0, 0], b"stm32-flash-programmer-cli");
+    for segment in segments {
+        for (index, chunk) in segment.data.chunks(RECORD_DATA_SIZE).enumerate() {
+            let address = segment.address + (index * RECORD_DATA_SIZE) as u32;
+            output += &srec_record('3', &address.to_be_bytes(), chunk);
+        }
+    }
+    output += &srec_record('7', &[0, 0, 0, 0], &[]);
+
+    output
+}
+
+// Writes a minimal ARM executable with one PT_LOAD program header and one
+// section per segment, so that both loaders and objcopy can read it
+fn to_elf(segments: &[Segment]) -> Vec<u8> {
+    const EHDR_SIZE: u32 = 52;
+    const PHDR_SIZE: u32 = 32;
+    const SHDR_SIZE: u32 = 40;
+    const EM_ARM: u16 = 40;
+    const EF_ARM_EABI_VER5: u32 = 0x05000000;
+
+    // section names: ".shstrtab" followed by ".sec0", ".sec1", ...
+    let mut names = b"\0.shstrtab\0".to_vec();
+    let mut name_offsets = Vec::new();
+    for index in 0..segments.len() {
+        name_offsets.push(names.len() as u32);
+        names.extend_from_slice(format!(".sec{index}\0").as_bytes());
+    }
+
+    let data_offset = EHDR_SIZE + PHDR_SIZE * segments.len() as u32;
+    let data_size: u32 = segments
+        .iter()
+        .map(|segment| segment.data.len() as u32)
+        .sum();
+    let names_offset = data_offset + data_size;
+    let section_headers_offset = (names_offset + names.len() as u32).next_multiple_of(4);
+    let section_count = segments.len() as u16 + 2;
+
+    let mut content = b"\x7FELF".to_vec();
+    // 32-bit, little endian, ELF version 1, padding
+    content.extend_from_slice(&[1, 1, 1]);
+    content.extend_from_slice(&[0; 9]);
+    content.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    content.extend_from_slice(&EM_ARM.to_le_bytes());
+    content.extend_from_slice(&1u32.to_le_bytes()); // version
+    content.extend_from_slice(&0u32.to_le_bytes()); // entry
+    content.extend_from_slice(&EHDR_SIZE.to_le_bytes());
+    content.extend_from_slice(&section_headers_offset.to_le_bytes());
+    content.extend_from_slice(&EF_ARM_EABI_VER5.to_le_bytes());
+    content.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+    content.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    content.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+    content.extend_from_slice(&(SHDR_SIZE as u16).to_le_bytes());
+    content.extend_from_slice(&section_count.to_le_bytes());
+    content.extend_from_slice(&(section_count - 1).to_le_bytes()); // .shstrtab
+
+    let mut offset = data_offset;
+    for segment in segments {
+        let size = segment.data.len() as u32;
+        for field in [
+            1, // PT_LOAD
+            offset,
+            segment.address,
+            segment.address,
+            size,
+            size,
+            5, // PF_R | PF_X
+            4,
+        ] {
+            content.extend_from_slice(&field.to_le_bytes());
+        }
+        offset += size;
+    }
+
+    for segment in segments {
+        content.extend_from_slice(&segment.data);
+    }
+    content.extend_from_slice(&names);
+    content.resize(section_headers_offset as usize, 0);
+
+    // null section
+    content.extend_from_slice(&[0; SHDR_SIZE as usize]);
+    let mut offset = data_offset;
+    for (segment, name) in segments.iter().zip(name_offsets) {
+        let size = segment.data.len() as u32;
+        for field in [
+            name,
+            1, // SHT_PROGBITS
+            6, // SHF_ALLOC | SHF_EXECINSTR
+            segment.address,
+            offset,
+            size,
+            0,
+            0,
+            4,
+            0,
+        ] {
+            content.extend_from_slice(&field.to_le_bytes());
+        }
+        offset += size;
+    }
+    for field in [
+        1, // ".shstrtab"
+        3, // SHT_STRTAB
+        0,
+        0,
+        names_offset,
+        names.len() as u32,
+        0,
+        0,
+        1,
+        0,
+    ] {
+        content.extend_from_slice(&field.to_le_bytes());
+    }
+
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(segments: &[Segment]) -> Vec<(u32, Vec<u8>)> {
+        segments
+            .iter()
+            .map(|segment| (segment.address, segment.data.clone()))
+            .collect()
+    }
+
+    fn error<T>(result: Result<T, String>) -> String {
+        result.err().expect("the content should be rejected")
+    }
+
+    fn sample() -> Vec<Segment> {
+        vec![
+            Segment {
+                address: 0x0800_8000,
+                data: (0..40).collect(),
+            },
+            Segment {
+                address: 0x0806_0000,
+                data: vec![0xAA, 0xBB, 0xCC],
+            },
+        ]
+    }
+
+    #[test]
+    fn intel_hex_joins_consecutive_records() {
+        let content = ":020000040800F2\n:048000000102030472\n:0280040005066F\n\n:018010000768\n:00000001FF\n:018010000768\n";
+        assert_eq!(
+            layout(&parse_intel_hex(content.as_bytes()).unwrap()),
+            vec![
+                (0x0800_8000, vec![1, 2, 3, 4, 5, 6]),
+                (0x0800_8010, vec![7])
+            ]
+        );
+    }
+
+    #[test]
+    fn intel_hex_rejects_invalid_records() {
+        for (content, reason) in [
+            ("020000040800F2", "missing ':'"),
+            (":020000040800F", "odd number of digits"),
+            (":0200000408G0F2", "invalid hex digit"),
+            (":030000040800F2", "wrong length"),
+            (":020000040800F3", "wrong checksum"),
+            (":00000006FA", "unsupported record type"),
+            (
+                ":02000004FFFFFC\n:10FFF000000102030405060708090A0B0C0D0E0F89",
+                "address space",
+            ),
+            (":0200000408é00F2", "invalid character"),
+        ] {
+            let error = error(parse_intel_hex(content.as_bytes()));
+            assert!(error.contains(reason), "{content}: {error}");
+        }
+    }
+
+    #[test]
+    fn srec_skips_header_and_start_records() {
+        let content = "S0060000686472BB\nS309080080000102030464\nS30708008004050661\nS104100009E2\nS7050800800072\n";
+        assert_eq!(
+            layout(&parse_srec(content.as_bytes()).unwrap()),
+            vec![(0x0800_8000, vec![1, 2, 3, 4, 5, 6]), (0x1000, vec![9])]
+        );
+    }
+
+    #[test]
+    fn srec_rejects_invalid_records() {
+        for (content, reason) in [
+            ("309080080000102030464", "missing 'S'"),
+            ("S", "missing record type"),
+            ("S30908008000010203046", "odd number of digits"),
+            ("S309080080000102030X64", "invalid hex digit"),
+            ("S30A080080000102030464", "wrong length"),
+            ("S309080080000102030465", "wrong checksum"),
+            ("S4030000FC", "unsupported record type"),
+            (
+                "S315FFFFFFF8000102030405060708090A0B0C0D0E0F7D",
+                "address space",
+            ),
+            ("S3é9080080000102030464", "invalid character"),
+        ] {
+            let error = error(parse_srec(content.as_bytes()));
+            assert!(error.contains(reason), "{content}: {error}");
+        }
+    }
+
+    #[test]
+    fn written_images_parse_back() {
+        let segments = sample();
+        let expected = layout(&segments);
+        assert_eq!(
+            layout(&parse_intel_hex(to_intel_hex(&segments).as_bytes()).unwrap()),
+            expected
+        );
+        assert_eq!(
+            layout(&parse_srec(to_srec(&segments).as_bytes()).unwrap()),
+            expected
+        );
+        assert_eq!(layout(&parse_elf(&to_elf(&segments)).unwrap()), expected);
+    }
+
+    #[test]
+    fn raw_binary_gaps_are_filled_up_to_a_limit() {
+        let mut image = MemoryImage::new();
+        image.add_segment(0x0800_0000, vec![1, 2]).unwrap();
+        image.add_segment(0x0800_0004, vec![3]).unwrap();
+        assert_eq!(to_bin(&image, 0xFF).unwrap(), [1, 2, 0xFF, 0xFF, 3]);
+
+        image.add_segment(0x2000_0000, vec![4]).unwrap();
+        let error = to_bin(&image, 0xFF).unwrap_err();
+        assert!(error.contains("--split"), "{error}");
+    }
+
+    #[test]
+    fn intel_hex_records_split_at_64k_boundaries() {
+        let segments = vec![Segment {
+            address: 0x0800_FFF8,
+            data: (0..16).collect(),
+        }];
+        let content = to_intel_hex(&segments);
+        assert!(content.contains(":020000040801F1"));
+        assert_eq!(
+            layout(&parse_intel_hex(content.as_bytes()).unwrap()),
+            layout(&segments)
+        );
+    }
+
+    #[test]
+    fn elf_rejects_invalid_files() {
+        let elf = to_elf(&sample());
+
+        assert!(parse_elf(b"not an elf file").is_err());
+        assert!(parse_elf(&elf[..40]).is_err());
+
+        let mut elf64 = elf.clone();
+        elf64[4] = 2;
+        assert!(error(parse_elf(&elf64)).contains("32-bit"));
+
+        // program header table past the end of the file
+        assert!(error(parse_elf(&elf[..60])).contains("program header"));
+
+        // first segment size larger than the file
+        let mut truncated = elf.clone();
+        truncated[52 + 16..52 + 20].copy_from_slice(&0x1000u32.to_le_bytes());
+        assert!(error(parse_elf(&truncated)).contains("segment 0"));
+    }
+
+    #[test]
+    fn overlapping_segments_are_rejected() {
+        let mut segments = sample();
+        segments[1].address = 0x0800_8020;
+        let error = error(parse_image(
+            Path::new("app.elf"),
+            to_elf(&segments),
+            ImageFormat::Elf,
+            0,
+        ));
+        assert!(
+            error.contains("app.elf") && error.contains("overlaps"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn raw_binaries_are_placed_at_the_base_address() {
+        let image = parse_image(
+            Path::new("app.bin"),
+            vec![1, 2],
+            ImageFormat::Bin,
+            0x0800_8000,
+        )
+        .unwrap();
+        assert_eq!(layout(image.segments()), vec![(0x0800_8000, vec![1, 2])]);
+    }
+
+    #[test]
+    fn format_is_guessed_from_the_content() {
+        let guess = |content: &[u8]| ImageFormat::from_content(content).name();
+        assert_eq!(guess(&to_elf(&sample())), "ELF");
+        assert_eq!(guess(b"\r\n:020000040800F2\n"), "Intel HEX");
+        assert_eq!(guess(b"S0060000686472BB\n"), "Motorola S-record");
+        assert_eq!(guess(b":\x00\x20"), "raw binary");
+        assert_eq!(guess(b"S\xFF"), "raw binary");
+        assert_eq!(guess(&[0x00, 0x00, 0x02, 0x20]), "raw binary");
+        assert_eq!(guess(b""), "raw binary");
+    }
+
+    #[test]
+    fn hex_numbers_are_parsed_with_or_without_prefix() {
+        assert_eq!(parse_hex_u32("0x08008000"), Ok(0x0800_8000));
+        assert_eq!(parse_hex_u32("0X1f"), Ok(0x1F));
+        assert_eq!(parse_hex_u32("8008000"), Ok(0x0800_8000));
+        assert!(parse_hex_u32("0x").is_err());
+        assert!(parse_hex_u32("0x100000000").is_err());
+    }
+
+    #[test]
+    fn sizes_are_decimal_or_prefixed_hex() {
+        assert_eq!(parse_size("256"), Ok(256));
+        assert_eq!(parse_size("0x100"), Ok(256));
+        assert_eq!(parse_size("0X1f"), Ok(31));
+        assert!(parse_size("1f").is_err());
+        assert!(parse_size("0x").is_err());
+        assert!(parse_size("-1").is_err());
+    }
+}
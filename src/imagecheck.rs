@@ -0,0 +1,153 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::device::{check_vector_table, VECTOR_TABLE_ALIGNMENT};
+use crate::memory::MemoryImage;
+
+// What to do when the vector table at the start of an image looks wrong,
+// e.g. because the image was linked for a different base address
+#[derive(Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImageCheck {
+    // don't look at the vector table
+    Off,
+    // print the problems and continue
+    Warn,
+    // refuse the write or the jump
+    Abort,
+}
+
+// Checks the vector table at the start of the image before it is written:
+// its alignment, the initial stack pointer and the reset handler, which has
+// to point into the image itself
+pub fn check_image(image: &MemoryImage, mode: ImageCheck) -> Result<(), String> {
+    let Some(segment) = image.segments().first() else {
+        return Ok(());
+    };
+    if segment.data.len() < 8 {
+        return report(
+            vec![format!(
+                "The image at 0x{:08X} is too small to contain a vector table!",
+                segment.address
+            )],
+            mode,
+        );
+    }
+
+    let word =
+        |offset: usize| u32::from_le_bytes(segment.data[offset..offset + 4].try_into().unwrap());
+    let (initial_sp, reset_handler) = (word(0), word(4));
+
+    let mut problems: Vec<String> = alignment_problem(segment.address).into_iter().collect();
+    if let Err(error) = check_vector_table(initial_sp, reset_handler) {
+        problems.push(error);
+    } else if !image
+        .segments()
+        .iter()
+        .any(|segment| (segment.address..segment.end()).contains(&(reset_handler & !1)))
+    {
+        problems.push(format!(
+            "Reset handler 0x{reset_handler:08X} points outside of the image (0x{:08X} - 0x{:08X}), is it linked for a different base address?",
+            segment.address,
+            image.end().unwrap_or_default() - 1
+        ));
+    }
+
+    report(problems, mode)
+}
+
+// Checks the address of the vector table an application is started from,
+// the vectors themselves are always checked before a jump
+pub fn check_run_target(base: u32, mode: ImageCheck) -> Result<(), String> {
+    report(alignment_problem(base).into_iter().collect(), mode)
+}
+
+fn alignment_problem(address: u32) -> Option<String> {
+    if address.is_multiple_of(VECTOR_TABLE_ALIGNMENT) {
+        return None;
+    }
+    Some(format!(
+        "Vector table at 0x{address:08X} is not aligned to {VECTOR_TABLE_ALIGNMENT} bytes, VTOR can't point to it!"
+    ))
+}
+
+fn report(problems: Vec<String>, mode: ImageCheck) -> Result<(), String> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+    match mode {
+        ImageCheck::Off => Ok(()),
+        ImageCheck::Warn => {
+            for problem in problems {
+                println!("Warning: {problem}");
+            }
+            Ok(())
+        }
+        ImageCheck::Abort => Err(format!(
+            "{}\nThe vector table check failed, use --image-check warn or off to continue anyway",
+            problems.join("\n")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a vector table with the initial stack pointer and the reset handler
+    // followed by the rest of a small application
+    fn image(address: u32, initial_sp: u32, reset_handler: u32) -> MemoryImage {
+        let mut data = Vec::new();
+        data.extend(initial_sp.to_le_bytes());
+        data.extend(reset_handler.to_le_bytes());
+        data.resize(0x400, 0);
+        let mut image = MemoryImage::new();
+        image.add_segment(address, data).unwrap();
+        image
+    }
+
+    #[test]
+    fn valid_image_passes() {
+        let image = image(0x0800_8000, 0x2002_0000, 0x0800_81C5);
+        assert!(check_image(&image, ImageCheck::Abort).is_ok());
+        assert!(check_image(&MemoryImage::new(), ImageCheck::Abort).is_ok());
+    }
+
+    #[test]
+    fn image_linked_for_another_base_is_rejected() {
+        let image = image(0x0800_C000, 0x2002_0000, 0x0800_81C5);
+        let error = check_image(&image, ImageCheck::Abort).unwrap_err();
+        assert!(
+            error.contains("points outside of the image (0x0800C000 - 0x0800C3FF)"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let image = image(0x0800_8100, 0x0800_0000, 0x0800_8100);
+        let error = check_image(&image, ImageCheck::Abort).unwrap_err();
+        assert!(error.contains("not aligned to 512 bytes"), "{error}");
+        assert!(error.contains("does not point into SRAM"), "{error}");
+
+        let mut small = MemoryImage::new();
+        small.add_segment(0x0800_8000, vec![0; 4]).unwrap();
+        let error = check_image(&small, ImageCheck::Abort).unwrap_err();
+        assert!(error.contains("too small"), "{error}");
+    }
+
+    #[test]
+    fn warn_and_off_modes_continue() {
+        let image = image(0x0800_8000, 0x2002_0000, 0x0800_81C4);
+        assert!(check_image(&image, ImageCheck::Abort).is_err());
+        assert!(check_image(&image, ImageCheck::Warn).is_ok());
+        assert!(check_image(&image, ImageCheck::Off).is_ok());
+    }
+
+    #[test]
+    fn run_target_has_to_be_aligned() {
+        assert!(check_run_target(0x0800_8000, ImageCheck::Abort).is_ok());
+        assert!(check_run_target(0x0800_8004, ImageCheck::Abort).is_err());
+        assert!(check_run_target(0x0800_8004, ImageCheck::Warn).is_ok());
+    }
+}
@@ -0,0 +1,108 @@
+use std::path::Path;
+
+use crate::device::{
+    check_flash_range, check_vector_table, is_flash_mem_address, APP_BASE_ADDRESS, FLASH_SECTORS,
+};
+use crate::image::{load_image, ImageFormat};
+use crate::protocol::get_crc;
+
+// Prints the layout of a firmware image without talking to a device: its
+// segments, the flash sectors it spans, whether it fits into flash, the
+// vector table and the CRC32 the bootloader would calculate. Returns false
+// when the image can't be loaded or doesn't fit.
+pub fn inspect_image(path: &Path, base: u32) -> bool {
+    let format = ImageFormat::from_path(path);
+    let image = match load_image(path, format, base) {
+        Ok(image) => image,
+        Err(error) => {
+            eprintln!("{error}");
+            return false;
+        }
+    };
+
+    println!("File: {} ({})", path.display(), format.name());
+    if image.is_empty() {
+        println!("The image contains no data");
+        return false;
+    }
+    let segments = image.segments();
+
+    println!("Segments:");
+    for segment in segments {
+        println!(
+            "  0x{:08X} - 0x{:08X}  {:>7} bytes  CRC32 0x{:08X}",
+            segment.address,
+            segment.end() - 1,
+            segment.data.len(),
+            get_crc(&segment.data)
+        );
+    }
+
+    println!("Total size: {} bytes", image.size());
+
+    let sectors: Vec<String> = FLASH_SECTORS
+        .iter()
+        .enumerate()
+        .filter(|(_, sector)| {
+            segments.iter().any(|segment| {
+                segment.address < sector.start + sector.size && segment.end() > sector.start
+            })
+        })
+        .map(|(index, _)| index.to_string())
+        .collect();
+    if sectors.is_empty() {
+        println!("Flash sectors: none");
+    } else {
+        println!("Flash sectors: {}", sectors.join(", "));
+    }
+
+    let errors: Vec<String> = segments
+        .iter()
+        .filter_map(|segment| check_flash_range(segment.address, segment.data.len()).err())
+        .collect();
+    let fits = errors.is_empty();
+    if fits {
+        println!("Fits in flash: yes");
+    } else {
+        println!("Fits in flash: no");
+        for error in errors {
+            println!("  {error}");
+        }
+    }
+    if segments
+        .iter()
+        .any(|segment| is_flash_mem_address(&segment.address) && segment.address < APP_BASE_ADDRESS)
+    {
+        println!(
+            "Warning: the image overlaps the bootloader sectors below 0x{APP_BASE_ADDRESS:08X}"
+        );
+    }
+
+    match segments.iter().find(|segment| segment.data.len() >= 8) {
+        Some(segment) => {
+            let word = |offset: usize| {
+                u32::from_le_bytes(segment.data[offset..offset + 4].try_into().unwrap())
+            };
+            let (initial_sp, reset_handler) = (word(0), word(4));
+            println!(
+                "Vector table at 0x{:08X}: initial SP 0x{initial_sp:08X}, reset handler 0x{reset_handler:08X}",
+                segment.address
+            );
+            if let Err(error) = check_vector_table(initial_sp, reset_handler) {
+                println!("Warning: {error}");
+            }
+        }
+        None => println!("Vector table: none, the image is too small"),
+    }
+
+    if segments.len() > 1 && fits {
+        // the bytes between the segments read back as erased flash
+        let contiguous = image.fill_gaps(0xFF);
+        println!(
+            "Image CRC32: 0x{:08X} (gaps filled with 0xFF)",
+            get_crc(&contiguous.segments()[0].data)
+        );
+    }
+
+    fits
+}
@@ -1,179 +1,585 @@
-use regex::Regex;
-use serialport::{available_ports, ClearBuffer, SerialPort};
-use std::fs::read;
+mod batch;
+mod bitcheck;
+mod capabilities;
+mod capture;
+mod config;
+mod convert;
+mod device;
+mod flash;
+mod image;
+mod imagecheck;
+mod inspect;
+mod memory;
+mod monitor;
+mod protocol;
+mod reset;
+mod scripting;
+mod serial;
+mod session;
+mod timeouts;
+mod trace;
+
+#[cfg(test)]
+mod fakeport;
+
+use bitcheck::BitCheck;
+use capabilities::negotiate_capabilities;
+use capture::{create_capture, load_capture, ReplayPort};
+use clap::{Parser, Subcommand};
+use config::load_config;
+use convert::{convert_images, ConvertArgs};
+use device::{
+    check_flash_range, check_vector_table, is_flash_mem_address, ProgramUnit, APP_BASE_ADDRESS,
+};
+use flash::{read_vector_table, verify_image, write_image};
+use image::{parse_hex_u32, parse_image, parse_size, read_image_file, save_image, ImageFormat};
+use imagecheck::{check_image, check_run_target, ImageCheck};
+use inspect::inspect_image;
+use memory::MemoryImage;
+use monitor::{parse_monitor, MonitorSettings};
+use protocol::{
+    find_bootloader_command, u32_to_u8, BootloaderCommand, CMD_BL_FLASH_ERASE, CMD_BL_GET_DEV_ID,
+    CMD_BL_GET_HELP, CMD_BL_GET_RDP_LEVEL, CMD_BL_GET_RW_PROTECT, CMD_BL_GET_VER, CMD_BL_JMP_ADDR,
+    CMD_BL_MEM_READ, CMD_BL_MEM_WRITE, CMD_BL_SET_RW_PROTECT,
+};
+use reset::{parse_sequences, ResetSettings};
+use serial::{list_serial_ports, select_serial_port, PortSelection, SerialSettings};
+use serialport::{ClearBuffer, SerialPort};
+use session::{Session, Settings, DEFAULT_REENTRY_TIMEOUT};
 use std::io::{self, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::exit;
-
-struct BootloaderCommand {
-    code: u8,
-    length: u8,
+use std::str::SplitWhitespace;
+use std::time::Duration;
+use timeouts::Timeouts;
+use trace::create_trace;
+
+const COMMANDS: [(&str, &str, Option<&BootloaderCommand>); 15] = [
+    ("menu", "", None),
+    ("version", "", Some(&CMD_BL_GET_VER)),
+    ("commands", "", Some(&CMD_BL_GET_HELP)),
+    ("dev_id", "", Some(&CMD_BL_GET_DEV_ID)),
+    ("rdp", "", Some(&CMD_BL_GET_RDP_LEVEL)),
+    ("jmp", "[address]", Some(&CMD_BL_JMP_ADDR)),
+    ("run", "[base]", Some(&CMD_BL_JMP_ADDR)),
+    ("erase", "[sector] [count]", Some(&CMD_BL_FLASH_ERASE)),
+    (
+        "write",
+        "[filename] [address] [offset=N] [length=N]",
+        Some(&CMD_BL_MEM_WRITE),
+    ),
+    ("read", "[address] [length]", Some(&CMD_BL_MEM_READ)),
+    (
+        "verify",
+        "[filename] [address] [offset=N] [length=N]",
+        Some(&CMD_BL_MEM_READ),
+    ),
+    (
+        "dump",
+        "[address] [length] [filename]",
+        Some(&CMD_BL_MEM_READ),
+    ),
+    ("set_prot", "[sectors] [w|rw]", Some(&CMD_BL_SET_RW_PROTECT)),
+    ("get_prot", "", Some(&CMD_BL_GET_RW_PROTECT)),
+    ("quit", "", None),
+];
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    port_selection: PortSelection,
+
+    #[command(flatten)]
+    serial_settings: SerialSettings,
+
+    #[command(flatten)]
+    reset_settings: ResetSettings,
+
+    #[command(flatten)]
+    monitor_settings: MonitorSettings,
+
+    /// Config file with default settings [default: stm32-flash-programmer.toml if it exists]
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Run the commands from a file instead of starting the interactive prompt,
+    /// files with the .rhai extension are executed as Rhai scripts
+    #[arg(short, long)]
+    script: Option<PathBuf>,
+
+    /// Continue executing the script after a failed command
+    #[arg(long, requires = "script")]
+    keep_going: bool,
+
+    /// Log every frame exchanged with the bootloader to a file or to stderr
+    /// when no file is given
+    #[arg(long, value_name = "FILE", num_args = 0..=1, default_missing_value = "-")]
+    trace: Option<PathBuf>,
+
+    /// Expect a CRC32 after every bootloader reply and verify it,
+    /// requires a bootloader built with reply CRCs
+    #[arg(long)]
+    reply_crc: bool,
+
+    /// Write the gaps between the segments of an image as 0xFF instead of
+    /// skipping them
+    #[arg(long)]
+    fill_gaps: bool,
+
+    /// Flash programming unit, writes are padded with 0xFF to multiples of it
+    /// [default: byte]
+    #[arg(long, value_enum)]
+    program_unit: Option<ProgramUnit>,
+
+    /// Pad writes starting at an address not aligned to the programming unit
+    /// with 0xFF instead of rejecting them
+    #[arg(long)]
+    fix_alignment: bool,
+
+    /// Read the flash before writing and abort, erase the affected sectors or
+    /// rewrite them preserving their other contents, when bits would have to
+    /// change from 0 to 1 [default: abort]
+    #[arg(long, value_enum)]
+    bit_check: Option<BitCheck>,
+
+    /// Check the vector table of images before writing them and of
+    /// applications before running them: alignment, initial stack pointer
+    /// and a reset handler inside the image [default: off]
+    #[arg(long, value_enum)]
+    image_check: Option<ImageCheck>,
+
+    /// Give up waiting for the device to re-enter the bootloader after a jump
+    /// after this many milliseconds [default: 30000]
+    #[arg(long, value_name = "MS")]
+    reentry_timeout_ms: Option<u64>,
+
+    /// Record the session to a capture file which can be replayed later
+    #[arg(long, value_name = "FILE")]
+    capture: Option<PathBuf>,
+
+    /// Replay a capture file instead of talking to a device, runs the recorded
+    /// commands or the given script against the recorded replies
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["port", "auto", "list_ports", "capture"])]
+    replay: Option<PathBuf>,
 }
 
-const CMD_BL_GET_VER: BootloaderCommand = BootloaderCommand {
-    code: 0xA1,
-    length: 6,
-};
-const CMD_BL_GET_HELP: BootloaderCommand = BootloaderCommand {
-    code: 0xA2,
-    length: 6,
-};
-const CMD_BL_GET_DEV_ID: BootloaderCommand = BootloaderCommand {
-    code: 0xA3,
-    length: 6,
-};
-const CMD_BL_GET_RDP_LEVEL: BootloaderCommand = BootloaderCommand {
-    code: 0xA4,
-    length: 6,
-};
-const CMD_BL_JMP_ADDR: BootloaderCommand = BootloaderCommand {
-    code: 0xA5,
-    length: 10,
-};
-const CMD_BL_FLASH_ERASE: BootloaderCommand = BootloaderCommand {
-    code: 0xA6,
-    length: 8,
-};
-const CMD_BL_MEM_WRITE: BootloaderCommand = BootloaderCommand {
-    code: 0xA7,
-    length: 11,
-};
-const CMD_BL_MEM_READ: BootloaderCommand = BootloaderCommand {
-    code: 0xA8,
-    length: 11,
-};
-const CMD_BL_SET_RW_PROTECT: BootloaderCommand = BootloaderCommand {
-    code: 0xA9,
-    length: 8,
-};
-const CMD_BL_GET_RW_PROTECT: BootloaderCommand = BootloaderCommand {
-    code: 0xAA,
-    length: 6,
-};
+// Commands working on files only, without connecting to a device
+#[derive(Subcommand)]
+enum Command {
+    /// Show the segments, flash sectors, vector table and CRC32 of a
+    /// firmware image (.bin, .hex or .elf)
+    Inspect {
+        file: PathBuf,
+
+        /// Address of raw binary images (hex)
+        #[arg(long, value_parser = parse_hex_u32, default_value = "0x08008000")]
+        base: u32,
+    },
+
+    /// Convert and merge firmware images between raw binary, Intel HEX,
+    /// S-record and ELF
+    Convert(ConvertArgs),
+}
 
 fn main() {
-    start_program();
+    let args = Args::parse();
+    start_program(args);
 }
 
-fn start_program() {
+fn start_program(args: Args) {
     display_program_name();
 
-    let serial_devices = get_available_serial_ports();
-
-    if serial_devices.is_empty() {
-        eprintln!("No available serial devices!");
-        exit(0);
+    if let Some(command) = &args.command {
+        let success = match command {
+            Command::Inspect { file, base } => inspect_image(file, *base),
+            Command::Convert(convert_args) => convert_images(convert_args),
+        };
+        exit(if success { 0 } else { 1 });
     }
 
-    println!("Available serial devices:");
-    for (index, name) in serial_devices.iter().enumerate() {
-        println!("{index}: {name}");
+    let config = match load_config(args.config.as_deref()) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{error}");
+            exit(1);
+        }
+    };
+    let serial_settings = args.serial_settings.or(config.serial);
+    let reset = match parse_sequences(&args.reset_settings.or(config.reset)) {
+        Ok(reset) => reset,
+        Err(error) => {
+            eprintln!("{error}");
+            exit(1);
+        }
+    };
+    let monitor = match parse_monitor(&args.monitor_settings) {
+        Ok(monitor) => monitor,
+        Err(error) => {
+            eprintln!("{error}");
+            exit(1);
+        }
+    };
+    let trace = match args.trace.as_deref().map(create_trace).transpose() {
+        Ok(trace) => trace,
+        Err(error) => {
+            eprintln!("{error}");
+            exit(1);
+        }
+    };
+    let settings = Settings {
+        reply_crc: args.reply_crc || config.reply_crc,
+        fill_gaps: args.fill_gaps || config.fill_gaps,
+        program_unit: args
+            .program_unit
+            .or(config.program_unit)
+            .map_or(1, |unit| unit.size()),
+        fix_alignment: args.fix_alignment || config.fix_alignment,
+        bit_check: args
+            .bit_check
+            .or(config.bit_check)
+            .unwrap_or(BitCheck::Abort),
+        image_check: args
+            .image_check
+            .or(config.image_check)
+            .unwrap_or(ImageCheck::Off),
+        timeouts: Timeouts::new(serial_settings.timeout(), config.timeouts),
+        reentry_timeout: args
+            .reentry_timeout_ms
+            .map_or(DEFAULT_REENTRY_TIMEOUT, Duration::from_millis),
+        reset,
+        monitor,
+        trace,
+    };
+
+    if args.port_selection.list_ports {
+        list_serial_ports(&args.port_selection, &serial_settings, &settings);
+        exit(0);
     }
 
-    let mut port;
-    print!("Choose your device from the list: ");
-    io::stdout().flush().unwrap();
-
-    loop {
-        let mut serial_port_name = String::new();
-        io::stdin().read_line(&mut serial_port_name).unwrap();
-        let serial_port_name = serial_port_name.trim().to_string();
+    let mut replay_commands = None;
+    let port: Box<dyn SerialPort> = match &args.replay {
+        Some(path) => match load_capture(path) {
+            Ok(capture) => {
+                println!("Replaying capture file '{}'", path.display());
+                replay_commands = Some(capture.commands.clone());
+                Box::new(ReplayPort::new(capture))
+            }
+            Err(error) => {
+                eprintln!("{error}");
+                exit(1);
+            }
+        },
+        None => select_serial_port(&args.port_selection, &serial_settings, &settings),
+    };
+    port.clear(ClearBuffer::Input).unwrap();
+    let mut session = Session::new(port, settings);
 
-        if !serial_devices.contains(&serial_port_name) {
-            eprintln!("'{serial_port_name}' not found in the list of available ports");
-            print!("Try again: ");
-            io::stdout().flush().unwrap();
-            continue;
+    // started only now, the frames probing the ports for --auto don't belong
+    // to the session being replayed
+    if let Some(path) = &args.capture {
+        match create_capture(path) {
+            Ok(capture) => session.capture = Some(capture),
+            Err(error) => {
+                eprintln!("{error}");
+                exit(1);
+            }
         }
+    }
+    negotiate_capabilities(&mut session);
 
-        let serial_port_name = serial_port_name.trim().to_string();
-
-        port = match serialport::new(&serial_port_name, 115200).open() {
-            Ok(p) => p,
-            Err(error) => {
-                eprintln!("Failed to open {serial_port_name}: {}", error.description);
-                print!("Try again: ");
-                io::stdout().flush().unwrap();
-                continue;
+    if let Some(script) = &args.script {
+        let success = if script.extension().is_some_and(|ext| ext == "rhai") {
+            if args.keep_going {
+                eprintln!("--keep-going has no effect on Rhai scripts, use try/catch instead");
             }
+            scripting::run_script(script, session)
+        } else {
+            let success = batch::run_script(
+                script,
+                |cmd| parse_command(cmd, &mut session, false),
+                args.keep_going,
+            );
+            session.leave_bootloader();
+            success
         };
-
-        break;
+        exit(if success { 0 } else { 1 });
     }
 
-    port.set_timeout(std::time::Duration::from_secs(2)).unwrap();
-    port.clear(ClearBuffer::Input).unwrap();
+    if let Some(commands) = replay_commands {
+        let steps: Vec<(usize, &str)> = commands
+            .iter()
+            .enumerate()
+            .map(|(index, cmd)| (index + 1, cmd.as_str()))
+            .collect();
+        let success =
+            batch::run_commands(&steps, |cmd| parse_command(cmd, &mut session, false), true);
+        exit(if success { 0 } else { 1 });
+    }
 
     println!();
-    display_available_commands();
+    display_available_commands(&session);
     loop {
         let cmd = choose_command();
-        parse_command(&cmd, port.as_mut());
-        if let Err(error) = port.clear(ClearBuffer::Input) {
-            eprintln!("Failed to clear the input buffer! {}", error.description);
+        parse_command(&cmd, &mut session, true);
+        // after a jump the prompt only comes back with the bootloader
+        if let Err(error) = session.wait_for_bootloader() {
+            eprintln!("{error}");
         }
     }
 }
 
-fn u32_to_u8(number: u32, index: u32) -> u8 {
-    (number >> (8 * (index - 1)) & 0xFF) as u8
+struct CommandArgs<'a> {
+    cmd: &'a str,
+    args: SplitWhitespace<'a>,
+    interactive: bool,
+    // the arguments used so far, given inline or entered at the prompts
+    resolved: Vec<String>,
 }
 
-fn is_flash_mem_address(addr: &u32) -> bool {
-    (0x08000000..=0x0807FFFF).contains(addr)
-}
+impl<'a> CommandArgs<'a> {
+    fn new(input: &'a str, interactive: bool) -> Self {
+        let mut args = input.split_whitespace();
+        let cmd = args.next().unwrap_or("");
+        CommandArgs {
+            cmd,
+            args,
+            interactive,
+            resolved: Vec::new(),
+        }
+    }
 
-fn get_crc(buff: &[u8]) -> u32 {
-    let mut crc: u32 = 0xFFFFFFFF;
-    for data in buff {
-        crc ^= *data as u32;
-        for _ in 0..32 {
-            if crc & 0x80000000 != 0 {
-                crc = (crc << 1) ^ 0x04C11DB7;
-            } else {
-                crc <<= 1;
-            }
+    fn next(&mut self, prompt: &str) -> Option<String> {
+        // use the argument given inline with the command and only prompt
+        // the user when it is missing
+        if let Some(arg) = self.optional() {
+            return Some(arg);
         }
+
+        if !self.interactive {
+            eprintln!("Missing arguments! Usage: {}", command_usage(self.cmd));
+            return None;
+        }
+
+        let mut input = String::new();
+        print!("{prompt}");
+        io::stdout().flush().unwrap();
+        io::stdin()
+            .read_line(&mut input)
+            .expect("Failed to read input");
+
+        // inline arguments can't contain spaces, lists entered at a prompt
+        // are recorded comma separated
+        let parts: Vec<&str> = input.split_whitespace().collect();
+        self.resolved.push(parts.join(","));
+        Some(input.trim().to_string())
     }
 
-    crc
+    // returns the argument given inline with the command, never prompts
+    fn optional(&mut self) -> Option<String> {
+        let arg = self.args.next()?.to_string();
+        self.resolved.push(arg.clone());
+        Some(arg)
+    }
+
+    // the command line with all arguments used inline, which runs the same
+    // command again without prompting
+    fn command_line(&self) -> String {
+        [self.cmd.to_string()]
+            .into_iter()
+            .chain(self.resolved.iter().cloned())
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
 }
 
-fn calc_checksum_and_send(data: &mut [u8], port: &mut dyn SerialPort) -> Option<std::io::Error> {
-    let cmd_len = data[0];
-    let mut crc_buffer = [0u8; 4];
-    // calculate crc on bytes [0 to CMD_BL_X_LEN - 4)
-    // to properly calculate the crc, it expects the first byte to be
-    // the length to follow which means we need to subtract one because
-    // we don't count the length itself
-    data[0] -= 1;
-    let crc32 = get_crc(&data[0..(cmd_len - 4) as usize]);
-    crc_buffer[0] = u32_to_u8(crc32, 1);
-    crc_buffer[1] = u32_to_u8(crc32, 2);
-    crc_buffer[2] = u32_to_u8(crc32, 3);
-    crc_buffer[3] = u32_to_u8(crc32, 4);
+// Loads the image file given as the next argument for write and verify. Raw
+// binaries are placed at the address given as the following argument, the
+// other formats carry their own addresses.
+fn load_command_image(
+    args: &mut CommandArgs,
+    operation: &str,
+    settings: &Settings,
+) -> Option<MemoryImage> {
+    let filename = args.next("Enter filename: ")?;
+    let path = Path::new(&filename);
+
+    // "-" reads the image from stdin, the format is guessed from the content
+    let (content, format) = if filename == "-" {
+        if args.interactive {
+            eprintln!("Images can only be read from stdin with --script!");
+            return None;
+        }
+        match read_image_file(path) {
+            Ok(content) => {
+                let format = ImageFormat::from_content(&content);
+                println!(
+                    "Read {} bytes from stdin ({})",
+                    content.len(),
+                    format.name()
+                );
+                (content, format)
+            }
+            Err(error) => {
+                eprintln!("{error}");
+                return None;
+            }
+        }
+    } else {
+        if !path.exists() {
+            eprintln!("File '{filename}' does not exist!");
+            return None;
+        }
+        match read_image_file(path) {
+            Ok(content) => (content, ImageFormat::from_path(path)),
+            Err(error) => {
+                eprintln!("{error}");
+                return None;
+            }
+        }
+    };
+
+    let base_address = if format == ImageFormat::Bin {
+        let prompt = format!("Enter memory address at which to start {operation}: ");
+        let input = args.next(&prompt)?;
+        match parse_hex_u32(&input) {
+            Ok(addr) => addr,
+            Err(_) => {
+                eprintln!("Invalid hex number!");
+                return None;
+            }
+        }
+    } else {
+        0
+    };
+
+    let content = match slice_file_content(args, &filename, format, content) {
+        Ok(content) => content,
+        Err(error) => {
+            eprintln!("{error}");
+            return None;
+        }
+    };
+
+    let mut image = match parse_image(path, content, format, base_address) {
+        Ok(image) => image,
+        Err(error) => {
+            eprintln!("{error}");
+            return None;
+        }
+    };
+    if image.is_empty() {
+        eprintln!("File '{filename}' contains no data!");
+        return None;
+    }
+
+    for segment in image.segments() {
+        if let Err(error) = check_flash_range(segment.address, segment.data.len()) {
+            eprintln!("{error}");
+            return None;
+        }
+    }
 
-    // append crc_buffer to data
-    let data = [&data[0..(cmd_len - 4) as usize], &crc_buffer[..]].concat();
+    if settings.fill_gaps {
+        image = image.fill_gaps(0xFF);
+    }
+    Some(image)
+}
 
-    if let Err(error) = port.write_all(&data[0..1]) {
-        return Some(error);
+// Keeps the part of a raw binary selected by the optional offset=N and
+// length=N arguments, e.g. to skip the header of a release artifact
+fn slice_file_content(
+    args: &mut CommandArgs,
+    filename: &str,
+    format: ImageFormat,
+    content: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    let mut offset = None;
+    let mut length = None;
+    while let Some(arg) = args.optional() {
+        match arg.split_once('=') {
+            Some(("offset", value)) => offset = Some(parse_size(value)?),
+            Some(("length", value)) => length = Some(parse_size(value)?),
+            _ => {
+                return Err(format!(
+                    "Unknown argument '{arg}'! Usage: {}",
+                    command_usage(args.cmd)
+                ))
+            }
+        }
+    }
+    if offset.is_none() && length.is_none() {
+        return Ok(content);
+    }
+    // slicing the records of the other formats would silently drop data
+    if format != ImageFormat::Bin {
+        return Err(format!(
+            "offset= and length= only apply to raw binaries, '{filename}' is {}!",
+            format.name()
+        ));
     }
 
-    if let Err(error) = port.write_all(&data[1..((cmd_len) as usize)]) {
-        return Some(error);
+    let size = content.len();
+    let offset = offset.unwrap_or(0);
+    if offset >= size {
+        return Err(format!(
+            "Offset {offset} is past the end of '{filename}' ({size} bytes)!"
+        ));
+    }
+    let length = length.unwrap_or(size - offset);
+    if length == 0 || offset + length > size {
+        return Err(format!(
+            "Offset {offset} with length {length} does not fit into '{filename}' ({size} bytes)!"
+        ));
     }
 
-    None
+    println!(
+        "Using bytes 0x{offset:X} - 0x{:X} of '{filename}' ({length} bytes)",
+        offset + length - 1
+    );
+    Ok(content[offset..offset + length].to_vec())
 }
 
-fn parse_command(cmd: &str, port: &mut dyn SerialPort) {
+fn parse_command(input: &str, session: &mut Session, interactive: bool) -> bool {
+    let mut args = CommandArgs::new(input, interactive);
+    let success = run_command(&mut args, session);
+
+    // recorded once the arguments entered at the prompts are known, so that
+    // replaying the capture doesn't need to prompt
+    if let Some(capture) = &mut session.capture {
+        if !args.cmd.is_empty() {
+            capture.command(&args.command_line());
+        }
+    }
+    session.clear_input();
+    success
+}
+
+fn run_command(args: &mut CommandArgs, session: &mut Session) -> bool {
     let mut data_buffer = vec![0u8; 255];
+    let cmd = args.cmd;
+
+    if let Some((_, _, Some(command))) = COMMANDS.iter().find(|(name, _, _)| *name == cmd) {
+        if let Err(error) = session.wait_for_bootloader() {
+            eprintln!("{error}");
+            return false;
+        }
+        if !session.is_command_supported(command.code) {
+            eprintln!(
+                "Command '{cmd}' ({}) is not supported by the bootloader!",
+                command.name
+            );
+            return false;
+        }
+    }
 
     match cmd {
         "menu" => {
-            display_available_commands();
-            return;
+            display_available_commands(session);
+            return true;
         }
         "version" => {
             data_buffer[0] = CMD_BL_GET_VER.length;
@@ -195,12 +601,9 @@ fn parse_command(cmd: &str, port: &mut dyn SerialPort) {
             data_buffer[0] = CMD_BL_JMP_ADDR.length;
             data_buffer[1] = CMD_BL_JMP_ADDR.code;
 
-            print!("Enter memory address to jump to in hex: ");
-            io::stdout().flush().unwrap();
-            let mut input = String::new();
-            io::stdin()
-                .read_line(&mut input)
-                .expect("Failed to read input");
+            let Some(input) = args.next("Enter memory address to jump to in hex: ") else {
+                return false;
+            };
             let lowercase_input = input.to_lowercase();
             let input = lowercase_input.trim().trim_start_matches("0x");
 
@@ -211,49 +614,95 @@ fn parse_command(cmd: &str, port: &mut dyn SerialPort) {
                 data_buffer[5] = u32_to_u8(address_decimal, 4);
             } else {
                 eprintln!("Invalid hex address!");
-                return;
+                return false;
             }
         }
+        "run" => {
+            data_buffer[0] = CMD_BL_JMP_ADDR.length;
+            data_buffer[1] = CMD_BL_JMP_ADDR.code;
+
+            let base_address = match args.optional() {
+                Some(input) => {
+                    let lowercase_input = input.to_lowercase();
+                    match u32::from_str_radix(lowercase_input.trim_start_matches("0x"), 16) {
+                        Ok(addr) => addr,
+                        Err(_) => {
+                            eprintln!("Invalid hex address!");
+                            return false;
+                        }
+                    }
+                }
+                None => APP_BASE_ADDRESS,
+            };
+
+            if !is_flash_mem_address(&base_address) {
+                eprintln!("Memory address outside of FLASH memory bounds!");
+                return false;
+            }
+
+            let (initial_sp, reset_handler) = match read_vector_table(session, base_address) {
+                Ok(vectors) => vectors,
+                Err(error) => {
+                    eprintln!("{error}");
+                    return false;
+                }
+            };
+            println!(
+                "Vector table at 0x{base_address:08X}: initial SP 0x{initial_sp:08X}, reset handler 0x{reset_handler:08X}"
+            );
+            if let Err(error) = check_vector_table(initial_sp, reset_handler) {
+                eprintln!("{error}");
+                eprintln!("No valid application at 0x{base_address:08X}, not jumping.");
+                return false;
+            }
+            if let Err(error) = check_run_target(base_address, session.settings.image_check) {
+                eprintln!("{error}");
+                return false;
+            }
+
+            // the reset handler is jumped to as stored in the vector table,
+            // with the Thumb bit set
+            data_buffer[2] = u32_to_u8(reset_handler, 1);
+            data_buffer[3] = u32_to_u8(reset_handler, 2);
+            data_buffer[4] = u32_to_u8(reset_handler, 3);
+            data_buffer[5] = u32_to_u8(reset_handler, 4);
+        }
         "erase" => {
             data_buffer[0] = CMD_BL_FLASH_ERASE.length;
             data_buffer[1] = CMD_BL_FLASH_ERASE.code;
 
-            let mut input = String::new();
-            print!("Enter the sector number you want to start erasing from (0 to 7): ");
-            io::stdout().flush().unwrap();
-            io::stdin()
-                .read_line(&mut input)
-                .expect("Failed to read input");
+            let Some(input) =
+                args.next("Enter the sector number you want to start erasing from (0 to 7): ")
+            else {
+                return false;
+            };
 
-            let base_sector_number = match input.trim().parse() {
+            let base_sector_number = match input.parse() {
                 Ok(number) => number,
                 Err(_) => {
                     eprintln!("Invalid input!");
-                    return;
+                    return false;
                 }
             };
 
             if !(0..=7).contains(&base_sector_number) {
                 eprintln!("Invalid sector number!");
-                return;
+                return false;
             }
 
             const NUM_OF_FLASH_SECTORS: u8 = 8;
 
-            let mut input = String::new();
-            print!(
+            let Some(input) = args.next(&format!(
                 "Enter the amount of sectors to erase starting from {base_sector_number} sector: "
-            );
-            io::stdout().flush().unwrap();
-            io::stdin()
-                .read_line(&mut input)
-                .expect("Failed to read input");
+            )) else {
+                return false;
+            };
 
-            let num_of_sectors_to_erase = match input.trim().parse() {
+            let num_of_sectors_to_erase = match input.parse() {
                 Ok(number) => number,
                 Err(_) => {
                     eprintln!("Invalid input!");
-                    return;
+                    return false;
                 }
             };
 
@@ -261,101 +710,126 @@ fn parse_command(cmd: &str, port: &mut dyn SerialPort) {
                 eprintln!(
                     "Can't erase {num_of_sectors_to_erase} sectors starting at {base_sector_number} sector!"
                 );
-                return;
+                return false;
             }
 
             data_buffer[2] = base_sector_number;
             data_buffer[3] = num_of_sectors_to_erase;
         }
         "write" => {
-            data_buffer[1] = CMD_BL_MEM_WRITE.code;
-
-            let mut input = String::new();
-            print!("Enter filename: ");
-            io::stdout().flush().unwrap();
-            io::stdin()
-                .read_line(&mut input)
-                .expect("Failed to read input");
-
-            let filename = input.trim();
-
-            if !Path::new(filename).exists() {
-                eprintln!("File '{filename}' does not exist!");
-                return;
+            let Some(image) = load_command_image(args, "writing", &session.settings) else {
+                return false;
+            };
+            if let Err(error) = check_image(&image, session.settings.image_check) {
+                eprintln!("{error}");
+                return false;
             }
 
-            let mut input = String::new();
-            print!("Enter memory address at which to start writing: ");
-            io::stdout().flush().unwrap();
-            io::stdin()
-                .read_line(&mut input)
-                .expect("Failed to read input");
-
-            let input_lowercase = input.to_lowercase();
-            let input = input_lowercase.trim().trim_start_matches("0x");
+            return match write_image(session, &image) {
+                Ok(()) => {
+                    println!(
+                        "Bootloader memory write: SUCCESS ({} bytes in {} segments)",
+                        image.size(),
+                        image.segments().len()
+                    );
+                    true
+                }
+                Err(error) => {
+                    eprintln!("{error}");
+                    false
+                }
+            };
+        }
+        "verify" => {
+            let Some(image) = load_command_image(args, "verifying", &session.settings) else {
+                return false;
+            };
 
-            let base_address = match u32::from_str_radix(input, 16) {
+            return match verify_image(session, &image) {
+                Ok(()) => {
+                    println!("Verify: SUCCESS ({} bytes)", image.size());
+                    true
+                }
+                Err(error) => {
+                    eprintln!("{error}");
+                    false
+                }
+            };
+        }
+        "dump" => {
+            let Some(input) = args.next("Enter memory address to start dumping from (in hex): ")
+            else {
+                return false;
+            };
+            let base_address = match parse_hex_u32(&input) {
                 Ok(addr) => addr,
                 Err(_) => {
-                    eprintln!("Invalid hex number!");
-                    return;
+                    eprintln!("Invalid hex address!");
+                    return false;
                 }
             };
 
             if !is_flash_mem_address(&base_address) {
                 eprintln!("Memory address outside of FLASH memory bounds!");
-                return;
+                return false;
             }
 
-            let mut bytes: Vec<u8> = read(filename).unwrap();
-
-            let mut no_bytes_left_to_read = bytes.len();
-            let single_byte_write_no: u8 = 128;
-            let mut no_bytes_sent = 0;
-
-            while no_bytes_left_to_read > 0 {
-                let no_bytes_to_be_send = if no_bytes_left_to_read >= single_byte_write_no as usize
-                {
-                    single_byte_write_no
-                } else {
-                    no_bytes_left_to_read as u8
-                };
-
-                data_buffer[0] = CMD_BL_MEM_WRITE.length + no_bytes_to_be_send;
-                data_buffer[2] = u32_to_u8(base_address + no_bytes_sent, 1);
-                data_buffer[3] = u32_to_u8(base_address + no_bytes_sent, 2);
-                data_buffer[4] = u32_to_u8(base_address + no_bytes_sent, 3);
-                data_buffer[5] = u32_to_u8(base_address + no_bytes_sent, 4);
-
-                data_buffer[6] = no_bytes_to_be_send;
-
-                data_buffer[7..(no_bytes_to_be_send as usize + 7)]
-                    .copy_from_slice(&bytes[..(no_bytes_to_be_send as usize)]);
+            let Some(input) = args.next("Enter how many bytes to dump: ") else {
+                return false;
+            };
+            let length = match parse_size(&input) {
+                Ok(num) => num,
+                Err(_) => {
+                    eprintln!("Invalid input!");
+                    return false;
+                }
+            };
+            if let Err(error) = check_flash_range(base_address, length) {
+                eprintln!("{error}");
+                return false;
+            }
 
-                bytes = bytes[no_bytes_to_be_send as usize..].to_vec();
+            let Some(filename) = args.next("Enter filename: ") else {
+                return false;
+            };
 
-                if let Some(error) = calc_checksum_and_send(&mut data_buffer, port) {
-                    eprintln!("Critical error: '{}'\nExiting...", error.kind());
-                    exit(1);
-                } else if !process_bootloader_reply(data_buffer[1], port) {
-                    return;
+            let bytes = match session.read_memory(base_address, length) {
+                Ok(bytes) => bytes,
+                Err(error) => {
+                    eprintln!("{error}");
+                    return false;
                 }
+            };
 
-                no_bytes_sent += no_bytes_to_be_send as u32;
-                no_bytes_left_to_read -= no_bytes_to_be_send as usize;
+            let mut image = MemoryImage::new();
+            if let Err(error) = image.add_segment(base_address, bytes) {
+                eprintln!("{error}");
+                return false;
             }
-            return;
+            let path = Path::new(&filename);
+            let format = ImageFormat::from_path(path);
+            return match save_image(path, format, &image, 0xFF) {
+                Ok(()) => {
+                    println!(
+                        "Dumped {length} bytes from 0x{base_address:08X} to '{filename}' ({})",
+                        format.name()
+                    );
+                    true
+                }
+                Err(error) => {
+                    eprintln!("{error}");
+                    false
+                }
+            };
         }
         "read" => {
             data_buffer[0] = CMD_BL_MEM_READ.length;
             data_buffer[1] = CMD_BL_MEM_READ.code;
 
-            let mut input = String::new();
-            print!("Enter memory address to start reading from (in hex): ");
-            io::stdout().flush().unwrap();
-            io::stdin()
-                .read_line(&mut input)
-                .expect("Failed to read input");
+            let Some(input) = args.next("Enter memory address to start reading from (in hex): ")
+            else {
+                return false;
+            };
 
             let input_lowercase = input.to_lowercase();
             let input = input_lowercase.trim().trim_start_matches("0x");
@@ -364,35 +838,35 @@ fn parse_command(cmd: &str, port: &mut dyn SerialPort) {
                 Ok(addr) => addr,
                 Err(_) => {
                     eprintln!("Invalid hex address!");
-                    return;
+                    return false;
                 }
             };
 
             if !is_flash_mem_address(&base_address) {
                 eprintln!("Memory address outside of FLASH memory bounds!");
-                return;
+                return false;
             }
 
-            let mut input = String::new();
-
-            print!("Enter how many bytes to read: ");
-            io::stdout().flush().unwrap();
-            io::stdin()
-                .read_line(&mut input)
-                .expect("Failed to read input");
+            let Some(input) = args.next("Enter how many bytes to read: ") else {
+                return false;
+            };
 
             // TODO: Allow for bigger memory reads than u8
-            let num_of_bytes_to_read: u8 = match input.trim().parse() {
+            let num_of_bytes_to_read = match parse_size(&input) {
                 Ok(num) => num,
                 Err(_) => {
                     eprintln!("Invalid input!");
-                    return;
+                    return false;
                 }
             };
 
             if num_of_bytes_to_read > 254 {
                 eprintln!("Currently unable to read more than 254 bytes at a time!");
-                return;
+                return false;
+            }
+            if let Err(error) = check_flash_range(base_address, num_of_bytes_to_read) {
+                eprintln!("{error}");
+                return false;
             }
 
             data_buffer[2] = u32_to_u8(base_address, 1);
@@ -400,22 +874,23 @@ fn parse_command(cmd: &str, port: &mut dyn SerialPort) {
             data_buffer[4] = u32_to_u8(base_address, 3);
             data_buffer[5] = u32_to_u8(base_address, 4);
 
-            data_buffer[6] = num_of_bytes_to_read;
+            data_buffer[6] = num_of_bytes_to_read as u8;
         }
         "set_prot" => {
             data_buffer[0] = CMD_BL_SET_RW_PROTECT.length;
             data_buffer[1] = CMD_BL_SET_RW_PROTECT.code;
 
-            let mut input = String::new();
-            println!(
-                "Enter which sectors you want to set protection (0 to 7) separated by space: "
-            );
-            io::stdout().flush().unwrap();
-            io::stdin()
-                .read_line(&mut input)
-                .expect("Failed to read input");
+            let Some(input) = args.next(
+                "Enter which sectors you want to set protection (0 to 7) separated by space: ",
+            ) else {
+                return false;
+            };
 
-            let sector_numbers_str: Vec<&str> = input.trim().split(' ').collect();
+            // inline sectors are separated by commas, e.g. "set_prot 0,1 w"
+            let sector_numbers_str: Vec<&str> = input
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|number| !number.is_empty())
+                .collect();
 
             let mut sector_numbers: Vec<u8> = vec![];
 
@@ -424,13 +899,13 @@ fn parse_command(cmd: &str, port: &mut dyn SerialPort) {
                     Ok(val) => {
                         if val > 7 {
                             eprintln!("Encountered invalid sector number! Aborting...");
-                            return;
+                            return false;
                         }
                         val
                     }
                     Err(_) => {
                         eprintln!("Encountered invalid sector number! Aborting...");
-                        return;
+                        return false;
                     }
                 };
 
@@ -443,24 +918,25 @@ fn parse_command(cmd: &str, port: &mut dyn SerialPort) {
                 sectors |= 1 << (num);
             }
 
-            let mut input = String::new();
-            println!("Enter 1 for write or 2 for read/write: ");
-            io::stdout().flush().unwrap();
-            io::stdin()
-                .read_line(&mut input)
-                .expect("Failed to read input");
+            let Some(input) = args.next("Enter 1 (w) for write or 2 (rw) for read/write: ") else {
+                return false;
+            };
 
-            let prot_level = match input.trim().parse() {
-                Ok(val) => val,
-                Err(_) => {
-                    eprintln!("Invalid input!");
-                    return;
-                }
+            let prot_level = match input.as_str() {
+                "w" => 1,
+                "rw" => 2,
+                _ => match input.parse() {
+                    Ok(val) => val,
+                    Err(_) => {
+                        eprintln!("Invalid input!");
+                        return false;
+                    }
+                },
             };
 
             if !(1..=2).contains(&prot_level) {
                 println!("Incorrect protection level value!");
-                return;
+                return false;
             }
 
             data_buffer[2] = sectors;
@@ -471,100 +947,97 @@ fn parse_command(cmd: &str, port: &mut dyn SerialPort) {
             data_buffer[1] = CMD_BL_GET_RW_PROTECT.code;
         }
         "quit" => {
+            session.leave_bootloader();
             exit(0);
         }
         "" => {
-            return;
+            return true;
         }
         _ => {
             println!("Command '{cmd}' is not supported!");
-            return;
+            return false;
         }
     }
 
-    if let Some(error) = calc_checksum_and_send(&mut data_buffer, port) {
-        eprintln!("Critical error: '{}'\nExiting...", error.kind());
+    let timeout = session
+        .settings
+        .timeouts
+        .reply_timeout(data_buffer[1], &data_buffer[2..]);
+    if let Some(error) = session.send_command(&mut data_buffer, timeout) {
+        eprintln!("Critical error: '{}'", error.kind());
+        // scripts stop on their own and still print their summary
+        if !args.interactive {
+            return false;
+        }
+        eprintln!("Exiting...");
         exit(1);
-    } else {
-        process_bootloader_reply(data_buffer[1], port);
     }
+
+    process_bootloader_reply(data_buffer[1], &data_buffer[2..], session)
 }
 
-fn process_bootloader_reply(command: u8, port: &mut dyn SerialPort) -> bool {
-    let mut rcv_buffer = vec![0u8; 2];
-    if let Err(error) = port.read_exact(&mut rcv_buffer) {
-        eprintln!("Failed to read the bootloader reply: {error}");
-        println!("Make sure the device is in bootloader mode.");
-        return false;
-    }
-
-    if rcv_buffer[0] == 0xBB {
-        let reply_length = rcv_buffer[1] as usize;
-        if command == CMD_BL_GET_VER.code {
-            process_cmd_bl_get_ver(reply_length, port);
-        } else if command == CMD_BL_GET_HELP.code {
-            process_cmd_bl_get_help(reply_length, port);
-        } else if command == CMD_BL_GET_DEV_ID.code {
-            process_cmd_bl_get_dev_id(reply_length, port);
-        } else if command == CMD_BL_GET_RDP_LEVEL.code {
-            process_cmd_bl_get_rdp_level(reply_length, port);
-        } else if command == CMD_BL_JMP_ADDR.code {
-            process_cmd_bl_jmp_addr(reply_length, port);
-        } else if command == CMD_BL_FLASH_ERASE.code {
-            process_cmd_bl_flash_erase(reply_length, port);
-        } else if command == CMD_BL_MEM_WRITE.code {
-            process_cmd_bl_mem_write(reply_length, port);
-        } else if command == CMD_BL_MEM_READ.code {
-            process_cmd_bl_mem_read(reply_length, port);
-        } else if command == CMD_BL_SET_RW_PROTECT.code {
-            process_cmd_bl_set_rw_protect(reply_length, port);
-        } else if command == CMD_BL_GET_RW_PROTECT.code {
-            process_cmd_bl_get_rw_protect(reply_length, port);
-        } else {
-            println!("Unknown bootloader command");
+fn process_bootloader_reply(command: u8, args: &[u8], session: &mut Session) -> bool {
+    let reply = match session.read_bootloader_reply(command, args) {
+        Ok(reply) => reply,
+        Err(error) => {
+            eprintln!("{error}");
+            println!("Make sure the device is in bootloader mode.");
+            return false;
         }
-    } else if rcv_buffer[0] == 0xEE {
-        println!("CRC verification failed!");
+    };
+
+    if command == CMD_BL_GET_VER.code {
+        process_cmd_bl_get_ver(&reply)
+    } else if command == CMD_BL_GET_HELP.code {
+        process_cmd_bl_get_help(&reply)
+    } else if command == CMD_BL_GET_DEV_ID.code {
+        process_cmd_bl_get_dev_id(&reply)
+    } else if command == CMD_BL_GET_RDP_LEVEL.code {
+        process_cmd_bl_get_rdp_level(&reply)
+    } else if command == CMD_BL_JMP_ADDR.code {
+        process_cmd_bl_jmp_addr(&reply, session)
+    } else if command == CMD_BL_FLASH_ERASE.code {
+        process_cmd_bl_flash_erase(&reply)
+    } else if command == CMD_BL_MEM_READ.code {
+        process_cmd_bl_mem_read(&reply)
+    } else if command == CMD_BL_SET_RW_PROTECT.code {
+        process_cmd_bl_set_rw_protect(&reply)
+    } else if command == CMD_BL_GET_RW_PROTECT.code {
+        process_cmd_bl_get_rw_protect(&reply)
     } else {
-        println!("Unknown reply!");
+        println!("Unknown bootloader command");
+        false
     }
-
-    true
 }
 
-fn process_cmd_bl_get_ver(length: usize, port: &mut dyn SerialPort) {
-    let mut rcv_buffer = vec![0u8; length];
-    port.read_exact(&mut rcv_buffer).unwrap();
+fn process_cmd_bl_get_ver(rcv_buffer: &[u8]) -> bool {
     println!("Bootloader version: 0x{:02X}", rcv_buffer[0]);
+    true
 }
 
-fn process_cmd_bl_get_help(length: usize, port: &mut dyn SerialPort) {
-    let mut rcv_buffer = vec![0u8; length];
-    port.read_exact(&mut rcv_buffer).unwrap();
-    print!("Bootloader available commands: ");
+fn process_cmd_bl_get_help(rcv_buffer: &[u8]) -> bool {
+    println!("Bootloader available commands: ");
     for cmd in rcv_buffer {
-        print!("0x{cmd:02X} ");
+        match find_bootloader_command(*cmd) {
+            Some(command) => println!("0x{cmd:02X} {}", command.name),
+            None => println!("0x{cmd:02X} unknown"),
+        }
     }
-    println!();
+    true
 }
 
-fn process_cmd_bl_get_dev_id(length: usize, port: &mut dyn SerialPort) {
-    let mut rcv_buffer = vec![0u8; length];
-    port.read_exact(&mut rcv_buffer).unwrap();
+fn process_cmd_bl_get_dev_id(rcv_buffer: &[u8]) -> bool {
     let dev_id: u16 = (rcv_buffer[1] as u16) << 8 | rcv_buffer[0] as u16;
     println!("Bootloader device id: 0x{dev_id:04X}");
+    true
 }
 
-fn process_cmd_bl_get_rdp_level(length: usize, port: &mut dyn SerialPort) {
-    let mut rcv_buffer = vec![0u8; length];
-    port.read_exact(&mut rcv_buffer).unwrap();
+fn process_cmd_bl_get_rdp_level(rcv_buffer: &[u8]) -> bool {
     println!("Bootloader rdp level: 0x{:02X}", rcv_buffer[0]);
+    true
 }
 
-fn process_cmd_bl_jmp_addr(length: usize, port: &mut dyn SerialPort) {
-    let mut rcv_buffer = vec![0u8; length];
-    port.read_exact(&mut rcv_buffer).unwrap();
-
+fn process_cmd_bl_jmp_addr(rcv_buffer: &[u8], session: &mut Session) -> bool {
     let result;
     if rcv_buffer[0] == 0 {
         result = "SUCCESS".to_string();
@@ -576,14 +1049,13 @@ fn process_cmd_bl_jmp_addr(length: usize, port: &mut dyn SerialPort) {
 
     println!("Bootloader jump to address: {result}");
     if result == "SUCCESS" {
-        exit(0);
+        session.mark_app_running();
+        return session.run_monitor();
     }
+    false
 }
 
-fn process_cmd_bl_flash_erase(length: usize, port: &mut dyn SerialPort) {
-    let mut rcv_buffer = vec![0u8; length];
-    port.read_exact(&mut rcv_buffer).unwrap();
-
+fn process_cmd_bl_flash_erase(rcv_buffer: &[u8]) -> bool {
     let result;
     if rcv_buffer[0] == 0 {
         result = "SUCCESS".to_string();
@@ -594,25 +1066,10 @@ fn process_cmd_bl_flash_erase(length: usize, port: &mut dyn SerialPort) {
     }
 
     println!("Bootloader flash erase: {result}");
+    result == "SUCCESS"
 }
 
-fn process_cmd_bl_mem_write(length: usize, port: &mut dyn SerialPort) {
-    let mut rcv_buffer = vec![0u8; length];
-    port.read_exact(&mut rcv_buffer).unwrap();
-
-    if rcv_buffer[0] == 1 {
-        println!("Bootloader memory write: SUCCESS");
-    } else if rcv_buffer[0] == 0 {
-        println!("Bootloader memory write: FAILURE");
-    } else {
-        println!("Bootloader memory write: INVALID RESPONSE");
-    }
-}
-
-fn process_cmd_bl_mem_read(length: usize, port: &mut dyn SerialPort) {
-    let mut rcv_buffer = vec![0u8; length];
-    port.read_exact(&mut rcv_buffer).unwrap();
-
+fn process_cmd_bl_mem_read(rcv_buffer: &[u8]) -> bool {
     if rcv_buffer[0] == 1 {
         println!("Bootloader memory read: SUCCESS");
         println!("Memory content: ");
@@ -620,17 +1077,16 @@ fn process_cmd_bl_mem_read(length: usize, port: &mut dyn SerialPort) {
             print!("0x{byte:02X} ");
         }
         println!();
+        return true;
     } else if rcv_buffer[0] == 0 {
         println!("Bootloader memory read: FAILURE");
     } else {
         println!("Bootloader memory read: INVALID RESPONSE");
     }
+    false
 }
 
-fn process_cmd_bl_set_rw_protect(length: usize, port: &mut dyn SerialPort) {
-    let mut rcv_buffer = vec![0u8; length];
-    port.read_exact(&mut rcv_buffer).unwrap();
-
+fn process_cmd_bl_set_rw_protect(rcv_buffer: &[u8]) -> bool {
     let result;
     if rcv_buffer[0] == 1 {
         result = "SUCCESS".to_string();
@@ -641,12 +1097,10 @@ fn process_cmd_bl_set_rw_protect(length: usize, port: &mut dyn SerialPort) {
     }
 
     println!("Bootloader set r/w protection: {result}");
+    result == "SUCCESS"
 }
 
-fn process_cmd_bl_get_rw_protect(length: usize, port: &mut dyn SerialPort) {
-    let mut rcv_buffer = vec![0u8; length];
-    port.read_exact(&mut rcv_buffer).unwrap();
-
+fn process_cmd_bl_get_rw_protect(rcv_buffer: &[u8]) -> bool {
     println!("Bootloader get r/w protection: ");
     for (index, prot_level) in rcv_buffer.iter().enumerate() {
         let protection;
@@ -662,6 +1116,7 @@ fn process_cmd_bl_get_rw_protect(length: usize, port: &mut dyn SerialPort) {
 
         println!("sector nr {index}: {protection}");
     }
+    true
 }
 
 fn choose_command() -> String {
@@ -681,33 +1136,72 @@ fn display_program_name() {
     println!("#######################################\n");
 }
 
-fn display_available_commands() {
+fn command_usage(cmd: &str) -> String {
+    match COMMANDS.iter().find(|(name, _, _)| *name == cmd) {
+        Some((name, "", _)) => name.to_string(),
+        Some((name, args, _)) => format!("{name} {args}"),
+        None => cmd.to_string(),
+    }
+}
+
+fn display_available_commands(session: &Session) {
     println!("Available commands:");
-    println!("menu");
-    println!("version");
-    println!("commands");
-    println!("dev_id");
-    println!("rdp");
-    println!("jmp");
-    println!("erase");
-    println!("write");
-    println!("read");
-    println!("set_prot");
-    println!("get_prot");
-    println!("quit");
+    // commands the bootloader did not advertise are hidden
+    for (name, _, command) in COMMANDS {
+        if command.is_none_or(|command| session.is_command_supported(command.code)) {
+            println!("{}", command_usage(name));
+        }
+    }
+    println!("Missing arguments are prompted for, e.g. 'read 0x08000000 64' or 'set_prot 0,1 w'");
 }
 
-fn get_available_serial_ports() -> Vec<String> {
-    let pattern = Regex::new("/dev/tty[A-Za-z]*").unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // slices the content with the arguments following the file name and
+    // address of a write command
+    fn slice(arguments: &str, format: ImageFormat) -> Result<Vec<u8>, String> {
+        let input = format!("write app.bin 0x08008000 {arguments}");
+        let mut args = CommandArgs::new(&input, false);
+        args.optional();
+        args.optional();
+        slice_file_content(&mut args, "app.bin", format, (0..16).collect())
+    }
+
+    #[test]
+    fn whole_file_is_used_without_offset_and_length() {
+        assert_eq!(slice("", ImageFormat::Bin), Ok((0..16).collect()));
+    }
 
-    let ports = available_ports().unwrap();
-    let mut available = Vec::new();
-    for port in ports {
-        let port_name = port.port_name;
-        if pattern.is_match(&port_name) {
-            available.push(port_name);
+    #[test]
+    fn offset_and_length_select_a_slice() {
+        assert_eq!(
+            slice("offset=12", ImageFormat::Bin),
+            Ok(vec![12, 13, 14, 15])
+        );
+        assert_eq!(
+            slice("offset=0x4 length=2", ImageFormat::Bin),
+            Ok(vec![4, 5])
+        );
+        assert_eq!(slice("length=3", ImageFormat::Bin), Ok(vec![0, 1, 2]));
+    }
+
+    #[test]
+    fn slices_outside_of_the_file_are_rejected() {
+        for arguments in ["offset=16", "offset=8 length=9", "length=0", "length=17"] {
+            assert!(slice(arguments, ImageFormat::Bin).is_err(), "{arguments}");
         }
     }
 
-    available
+    #[test]
+    fn invalid_slice_arguments_are_rejected() {
+        assert!(slice("offset=1f", ImageFormat::Bin).is_err());
+        assert!(slice("skip=4", ImageFormat::Bin)
+            .unwrap_err()
+            .contains("Unknown argument"));
+        assert!(slice("offset=4", ImageFormat::IntelHex)
+            .unwrap_err()
+            .contains("only apply to raw binaries"));
+    }
 }
@@ -0,0 +1,304 @@
+// A contiguous block of bytes to be placed at the given address
+#[derive(Clone)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    pub fn end(&self) -> u32 {
+        self.address + self.data.len() as u32
+    }
+}
+
+// Sparse memory contents: segments sorted by address that never overlap,
+// adjacent segments are merged into one
+#[derive(Default, Clone)]
+pub struct MemoryImage {
+    segments: Vec<Segment>,
+}
+
+impl MemoryImage {
+    pub fn new() -> MemoryImage {
+        MemoryImage::default()
+    }
+
+    pub fn from_segments(segments: Vec<Segment>) -> Result<MemoryImage, String> {
+        let mut image = MemoryImage::new();
+        for segment in segments {
+            image.add_segment(segment.address, segment.data)?;
+        }
+        Ok(image)
+    }
+
+    pub fn add_segment(&mut self, address: u32, data: Vec<u8>) -> Result<(), String> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        if u32::try_from(data.len()).map_or(true, |size| address.checked_add(size).is_none()) {
+            return Err(format!(
+                "Segment at 0x{address:08X} with {} bytes extends past the end of the address space!",
+                data.len()
+            ));
+        }
+
+        let segment = Segment { address, data };
+        let index = self
+            .segments
+            .partition_point(|existing| existing.address < address);
+
+        for neighbour in [index.checked_sub(1), Some(index)].into_iter().flatten() {
+            if let Some(existing) = self.segments.get(neighbour) {
+                if segment.address < existing.end() && existing.address < segment.end() {
+                    return Err(format!(
+                        "Segment 0x{:08X} - 0x{:08X} overlaps segment 0x{:08X} - 0x{:08X}!",
+                        segment.address,
+                        segment.end() - 1,
+                        existing.address,
+                        existing.end() - 1
+                    ));
+                }
+            }
+        }
+
+        self.segments.insert(index, segment);
+
+        // merge with the following and the preceding segment when adjacent
+        if index + 1 < self.segments.len()
+            && self.segments[index].end() == self.segments[index + 1].address
+        {
+            let next = self.segments.remove(index + 1);
+            self.segments[index].data.extend(next.data);
+        }
+        if index > 0 && self.segments[index - 1].end() == self.segments[index].address {
+            let segment = self.segments.remove(index);
+            self.segments[index - 1].data.extend(segment.data);
+        }
+
+        Ok(())
+    }
+
+    pub fn merge(&mut self, other: MemoryImage) -> Result<(), String> {
+        for segment in other.segments {
+            self.add_segment(segment.address, segment.data)?;
+        }
+        Ok(())
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn start(&self) -> Option<u32> {
+        self.segments.first().map(|segment| segment.address)
+    }
+
+    pub fn end(&self) -> Option<u32> {
+        self.segments.last().map(Segment::end)
+    }
+
+    // number of bytes in all segments, without the gaps
+    pub fn size(&self) -> usize {
+        self.segments.iter().map(|segment| segment.data.len()).sum()
+    }
+
+    // Returns the image without the bytes in [start, end)
+    pub fn without(&self, start: u32, end: u32) -> MemoryImage {
+        let mut segments = Vec::new();
+        for segment in &self.segments {
+            if segment.address < start {
+                let size = (segment.end().min(start) - segment.address) as usize;
+                segments.push(Segment {
+                    address: segment.address,
+                    data: segment.data[..size].to_vec(),
+                });
+            }
+            if segment.end() > end {
+                let offset = (end.max(segment.address) - segment.address) as usize;
+                segments.push(Segment {
+                    address: segment.address + offset as u32,
+                    data: segment.data[offset..].to_vec(),
+                });
+            }
+        }
+        MemoryImage { segments }
+    }
+
+    // Returns a single segment image with the gaps filled with the given byte
+    pub fn fill_gaps(&self, fill: u8) -> MemoryImage {
+        match (self.start(), self.end()) {
+            (Some(start), Some(end)) => self.fill_ranges(&[(start, end)], fill),
+            _ => MemoryImage::new(),
+        }
+    }
+
+    // Extends every segment down and up to a multiple of the alignment,
+    // segments sharing an aligned block are joined with the fill byte
+    pub fn align(&self, alignment: u32, fill: u8) -> Result<MemoryImage, String> {
+        let mut ranges: Vec<(u32, u32)> = Vec::new();
+        for segment in &self.segments {
+            let start = segment.address - segment.address % alignment;
+            let Some(end) = segment.end().checked_next_multiple_of(alignment) else {
+                return Err(format!(
+                    "Segment 0x{:08X} - 0x{:08X} can't be padded to a multiple of {alignment} \
+                     bytes without passing the end of the address space!",
+                    segment.address,
+                    segment.end() - 1
+                ));
+            };
+            match ranges.last_mut() {
+                Some(last) if start <= last.1 => last.1 = end,
+                _ => ranges.push((start, end)),
+            }
+        }
+        Ok(self.fill_ranges(&ranges, fill))
+    }
+
+    // Builds an image with one segment per range, the ranges have to cover
+    // all segments
+    fn fill_ranges(&self, ranges: &[(u32, u32)], fill: u8) -> MemoryImage {
+        let mut segments: Vec<Segment> = ranges
+            .iter()
+            .map(|(start, end)| Segment {
+                address: *start,
+                data: vec![fill; (end - start) as usize],
+            })
+            .collect();
+
+        for segment in &self.segments {
+            let range = segments
+                .iter_mut()
+                .find(|range| range.address <= segment.address && segment.end() <= range.end())
+                .unwrap();
+            let offset = (segment.address - range.address) as usize;
+            range.data[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+        }
+
+        MemoryImage { segments }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(segments: &[(u32, &[u8])]) -> MemoryImage {
+        let mut image = MemoryImage::new();
+        for (address, data) in segments {
+            image.add_segment(*address, data.to_vec()).unwrap();
+        }
+        image
+    }
+
+    fn layout(image: &MemoryImage) -> Vec<(u32, Vec<u8>)> {
+        image
+            .segments()
+            .iter()
+            .map(|segment| (segment.address, segment.data.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn add_segment_sorts_and_merges_adjacent_segments() {
+        let image = build(&[(0x20, &[3, 4]), (0x10, &[1, 2]), (0x12, &[9]), (0x13, &[5])]);
+        assert_eq!(
+            layout(&image),
+            vec![(0x10, vec![1, 2, 9, 5]), (0x20, vec![3, 4])]
+        );
+
+        // filling the gap joins both neighbours
+        let mut image = image;
+        image.add_segment(0x14, vec![0; 12]).unwrap();
+        assert_eq!(image.segments().len(), 1);
+        assert_eq!(image.size(), 18);
+        assert_eq!((image.start(), image.end()), (Some(0x10), Some(0x22)));
+    }
+
+    #[test]
+    fn add_segment_ignores_empty_data() {
+        let image = build(&[(0x10, &[])]);
+        assert!(image.is_empty());
+        assert_eq!(image.end(), None);
+    }
+
+    #[test]
+    fn add_segment_rejects_overlaps() {
+        let mut image = build(&[(0x10, &[0; 4]), (0x20, &[0; 4])]);
+        assert!(image.add_segment(0x0E, vec![0; 3]).is_err());
+        assert!(image.add_segment(0x13, vec![0; 1]).is_err());
+        assert!(image.add_segment(0x1F, vec![0; 2]).is_err());
+        assert!(image.add_segment(0x00, vec![0; 0x30]).is_err());
+        assert_eq!(image.size(), 8);
+        assert!(image.add_segment(0x14, vec![0; 0x0C]).is_ok());
+    }
+
+    #[test]
+    fn add_segment_rejects_data_past_the_address_space() {
+        let mut image = MemoryImage::new();
+        assert!(image.add_segment(0xFFFF_FFF0, vec![0; 16]).is_err());
+        assert!(image.add_segment(0xFFFF_FFF0, vec![0; 15]).is_ok());
+        assert_eq!(image.end(), Some(0xFFFF_FFFF));
+    }
+
+    #[test]
+    fn without_cuts_the_range_out_of_every_segment() {
+        let image = build(&[(0x10, &[1, 2, 3, 4]), (0x20, &[5, 6, 7, 8])]);
+        assert_eq!(
+            layout(&image.without(0x12, 0x22)),
+            vec![(0x10, vec![1, 2]), (0x22, vec![7, 8])]
+        );
+        assert_eq!(
+            layout(&image.without(0x00, 0x14)),
+            vec![(0x20, vec![5, 6, 7, 8])]
+        );
+        assert_eq!(
+            layout(&image.without(0x21, 0x23)),
+            vec![(0x10, vec![1, 2, 3, 4]), (0x20, vec![5]), (0x23, vec![8])]
+        );
+        assert_eq!(layout(&image.without(0x14, 0x20)), layout(&image));
+        assert!(image.without(0x00, 0x30).is_empty());
+    }
+
+    #[test]
+    fn fill_gaps_returns_a_single_segment() {
+        let image = build(&[(0x10, &[1, 2]), (0x14, &[3])]);
+        assert_eq!(
+            layout(&image.fill_gaps(0xFF)),
+            vec![(0x10, vec![1, 2, 0xFF, 0xFF, 3])]
+        );
+        assert!(MemoryImage::new().fill_gaps(0xFF).is_empty());
+    }
+
+    #[test]
+    fn align_pads_segments_and_joins_shared_blocks() {
+        let image = build(&[(0x11, &[1, 2]), (0x16, &[3]), (0x21, &[4])]);
+        assert_eq!(
+            layout(&image.align(8, 0xFF).unwrap()),
+            vec![
+                (0x10, vec![0xFF, 1, 2, 0xFF, 0xFF, 0xFF, 3, 0xFF]),
+                (0x20, vec![0xFF, 4, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]),
+            ]
+        );
+
+        // segments in neighbouring blocks stay apart, aligned ones are unchanged
+        let image = build(&[(0x10, &[1, 2, 3, 4]), (0x18, &[5, 6, 7, 8])]);
+        assert_eq!(layout(&image.align(4, 0).unwrap()), layout(&image));
+    }
+
+    #[test]
+    fn align_past_the_end_of_the_address_space_is_an_error() {
+        let image = build(&[(0xFFFF_FFF0, &[1, 2, 3])]);
+        let error = image.align(0x10, 0xFF).err().unwrap();
+        assert!(error.contains("end of the address space"), "{error}");
+
+        let image = build(&[(0xFFFF_FFE0, &[1, 2, 3])]);
+        assert_eq!(
+            layout(&image.align(0x10, 0).unwrap()),
+            vec![(0xFFFF_FFE0, [&[1, 2, 3], &[0; 13][..]].concat())]
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use regex::Regex;
+use serialport::SerialPort;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+// how long a single read waits for application output, also the
+// granularity of the monitor timeout
+const MONITOR_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(clap::Args)]
+pub struct MonitorSettings {
+    /// Stay attached after a successful jump and print the application output
+    #[arg(long)]
+    pub monitor: bool,
+
+    /// Stop monitoring with success once a line of output matches this regex
+    #[arg(long, value_name = "REGEX", requires = "monitor")]
+    pub monitor_pattern: Option<String>,
+
+    /// Stop monitoring after this many milliseconds, a failure when
+    /// --monitor-pattern was given and did not match
+    #[arg(long, value_name = "MS", requires = "monitor")]
+    pub monitor_timeout_ms: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct Monitor {
+    pattern: Option<Regex>,
+    timeout: Option<Duration>,
+}
+
+// Returns None when --monitor is not enabled
+pub fn parse_monitor(settings: &MonitorSettings) -> Result<Option<Monitor>, String> {
+    if !settings.monitor {
+        return Ok(None);
+    }
+
+    let pattern = match &settings.monitor_pattern {
+        Some(pattern) => Some(
+            Regex::new(pattern)
+                .map_err(|error| format!("Invalid monitor pattern '{pattern}': {error}"))?,
+        ),
+        None => None,
+    };
+
+    Ok(Some(Monitor {
+        pattern,
+        timeout: settings.monitor_timeout_ms.map(Duration::from_millis),
+    }))
+}
+
+fn print_line(start: Instant, line: &[u8]) -> String {
+    let line = String::from_utf8_lossy(line)
+        .trim_end_matches('\r')
+        .to_string();
+    let elapsed = start.elapsed();
+    println!(
+        "[{:>4}.{:06}] {line}",
+        elapsed.as_secs(),
+        elapsed.subsec_micros()
+    );
+    line
+}
+
+// Prints the application output line by line with the time since the jump
+// until the pattern matches or the timeout expires. Runs until the program is
+// interrupted when neither is given. Returns false when the timeout expired
+// before the pattern matched.
+pub fn run_monitor(monitor: &Monitor, port: &mut dyn SerialPort) -> bool {
+    if let Err(error) = port.set_timeout(MONITOR_POLL_INTERVAL) {
+        eprintln!(
+            "Failed to set the serial port timeout! {}",
+            error.description
+        );
+        return false;
+    }
+
+    println!("Monitoring the application output...");
+    let start = Instant::now();
+    let mut line = Vec::new();
+    let mut buffer = [0u8; 256];
+
+    loop {
+        match port.read(&mut buffer) {
+            Ok(count) => {
+                for byte in &buffer[..count] {
+                    if *byte != b'\n' {
+                        line.push(*byte);
+                        continue;
+                    }
+
+                    let text = print_line(start, &line);
+                    line.clear();
+                    if let Some(pattern) = &monitor.pattern {
+                        if pattern.is_match(&text) {
+                            println!("Monitor pattern '{pattern}' matched");
+                            return true;
+                        }
+                    }
+                }
+                io::stdout().flush().unwrap();
+            }
+            Err(error) if error.kind() == io::ErrorKind::TimedOut => {}
+            Err(error) => {
+                eprintln!("Failed to read the application output! {error}");
+                return false;
+            }
+        }
+
+        if let Some(timeout) = monitor.timeout {
+            if start.elapsed() >= timeout {
+                if !line.is_empty() {
+                    print_line(start, &line);
+                }
+                return match &monitor.pattern {
+                    Some(pattern) => {
+                        eprintln!(
+                            "Monitor timeout after {} ms, pattern '{pattern}' did not match",
+                            timeout.as_millis()
+                        );
+                        false
+                    }
+                    None => {
+                        println!("Monitor timeout after {} ms", timeout.as_millis());
+                        true
+                    }
+                };
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+pub struct BootloaderCommand {
+    pub name: &'static str,
+    pub code: u8,
+    pub length: u8,
+}
+
+pub const CMD_BL_GET_VER: BootloaderCommand = BootloaderCommand {
+    name: "BL_GET_VER",
+    code: 0xA1,
+    length: 6,
+};
+pub const CMD_BL_GET_HELP: BootloaderCommand = BootloaderCommand {
+    name: "BL_GET_HELP",
+    code: 0xA2,
+    length: 6,
+};
+pub const CMD_BL_GET_DEV_ID: BootloaderCommand = BootloaderCommand {
+    name: "BL_GET_DEV_ID",
+    code: 0xA3,
+    length: 6,
+};
+pub const CMD_BL_GET_RDP_LEVEL: BootloaderCommand = BootloaderCommand {
+    name: "BL_GET_RDP_LEVEL",
+    code: 0xA4,
+    length: 6,
+};
+pub const CMD_BL_JMP_ADDR: BootloaderCommand = BootloaderCommand {
+    name: "BL_JMP_ADDR",
+    code: 0xA5,
+    length: 10,
+};
+pub const CMD_BL_FLASH_ERASE: BootloaderCommand = BootloaderCommand {
+    name: "BL_FLASH_ERASE",
+    code: 0xA6,
+    length: 8,
+};
+pub const CMD_BL_MEM_WRITE: BootloaderCommand = BootloaderCommand {
+    name: "BL_MEM_WRITE",
+    code: 0xA7,
+    length: 11,
+};
+pub const CMD_BL_MEM_READ: BootloaderCommand = BootloaderCommand {
+    name: "BL_MEM_READ",
+    code: 0xA8,
+    length: 11,
+};
+pub const CMD_BL_SET_RW_PROTECT: BootloaderCommand = BootloaderCommand {
+    name: "BL_SET_RW_PROTECT",
+    code: 0xA9,
+    length: 8,
+};
+pub const CMD_BL_GET_RW_PROTECT: BootloaderCommand = BootloaderCommand {
+    name: "BL_GET_RW_PROTECT",
+    code: 0xAA,
+    length: 6,
+};
+
+pub const BOOTLOADER_COMMANDS: [&BootloaderCommand; 10] = [
+    &CMD_BL_GET_VER,
+    &CMD_BL_GET_HELP,
+    &CMD_BL_GET_DEV_ID,
+    &CMD_BL_GET_RDP_LEVEL,
+    &CMD_BL_JMP_ADDR,
+    &CMD_BL_FLASH_ERASE,
+    &CMD_BL_MEM_WRITE,
+    &CMD_BL_MEM_READ,
+    &CMD_BL_SET_RW_PROTECT,
+    &CMD_BL_GET_RW_PROTECT,
+];
+
+// maximum number of data bytes transferred by a single read or write command
+pub const MAX_DATA_CHUNK_SIZE: u8 = 128;
+
+pub fn find_bootloader_command(code: u8) -> Option<&'static BootloaderCommand> {
+    BOOTLOADER_COMMANDS
+        .into_iter()
+        .find(|command| command.code == code)
+}
+
+pub fn u32_to_u8(number: u32, index: u32) -> u8 {
+    (number >> (8 * (index - 1)) & 0xFF) as u8
+}
+
+pub fn get_crc(buff: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFFFFFF;
+    for data in buff {
+        crc ^= *data as u32;
+        for _ in 0..32 {
+            if crc & 0x80000000 != 0 {
+                crc = (crc << 1) ^ 0x04C11DB7;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+
+    crc
+}
@@ -0,0 +1,213 @@
+use serde::Deserialize;
+use serialport::SerialPort;
+use std::thread::sleep;
+use std::time::Duration;
+
+// Built-in DTR/RTS sequences, named after the signal wired to NRST and the
+// one wired to BOOT0. A level of 1 asserts the signal.
+const PRESETS: [(&str, &str); 4] = [
+    // hold BOOT0 high while releasing the reset to start the bootloader
+    (
+        "rts-reset-dtr-boot",
+        "dtr=1,rts=1,wait=100,rts=0,wait=100,dtr=0",
+    ),
+    (
+        "dtr-reset-rts-boot",
+        "rts=1,dtr=1,wait=100,dtr=0,wait=100,rts=0",
+    ),
+    // reset with BOOT0 low to start the application
+    ("rts-reset", "dtr=0,rts=1,wait=100,rts=0,wait=100"),
+    ("dtr-reset", "rts=0,dtr=1,wait=100,dtr=0,wait=100"),
+];
+
+// DTR/RTS sequences given on the command line or in the [reset] table of
+// the config file, either a preset name or a list of steps such as
+// "dtr=1,rts=1,wait=50,rts=0"
+#[derive(clap::Args, Deserialize, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ResetSettings {
+    /// DTR/RTS sequence entering the bootloader after opening the port,
+    /// a preset (rts-reset-dtr-boot, dtr-reset-rts-boot) or steps like
+    /// "dtr=1,rts=1,wait=100,rts=0"
+    #[arg(long, value_name = "SEQUENCE")]
+    pub entry_sequence: Option<String>,
+
+    /// DTR/RTS sequence applied when the session ends, a preset
+    /// (rts-reset, dtr-reset) or steps like "rts=1,wait=100,rts=0"
+    #[arg(long, value_name = "SEQUENCE")]
+    pub exit_sequence: Option<String>,
+}
+
+impl ResetSettings {
+    pub fn or(self, fallback: ResetSettings) -> ResetSettings {
+        ResetSettings {
+            entry_sequence: self.entry_sequence.or(fallback.entry_sequence),
+            exit_sequence: self.exit_sequence.or(fallback.exit_sequence),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Step {
+    Dtr(bool),
+    Rts(bool),
+    Wait(Duration),
+}
+
+#[derive(Clone)]
+pub struct Sequences {
+    entry: Vec<Step>,
+    exit: Vec<Step>,
+}
+
+pub fn parse_sequences(settings: &ResetSettings) -> Result<Sequences, String> {
+    let parse = |sequence: &Option<String>| match sequence {
+        Some(sequence) => parse_sequence(sequence),
+        None => Ok(Vec::new()),
+    };
+
+    Ok(Sequences {
+        entry: parse(&settings.entry_sequence)?,
+        exit: parse(&settings.exit_sequence)?,
+    })
+}
+
+fn parse_sequence(sequence: &str) -> Result<Vec<Step>, String> {
+    let steps = match PRESETS.iter().find(|(name, _)| *name == sequence) {
+        Some((_, steps)) => steps,
+        None => sequence,
+    };
+
+    steps
+        .split(',')
+        .map(str::trim)
+        .filter(|step| !step.is_empty())
+        .map(|step| {
+            let invalid_step = || format!("Invalid DTR/RTS sequence step '{step}' in '{sequence}'");
+            let (signal, value) = step.split_once('=').ok_or_else(invalid_step)?;
+            let level = match value.trim() {
+                "1" => Ok(true),
+                "0" => Ok(false),
+                _ => Err(invalid_step()),
+            };
+            match signal.trim().to_lowercase().as_str() {
+                "dtr" => Ok(Step::Dtr(level?)),
+                "rts" => Ok(Step::Rts(level?)),
+                "wait" => match value.trim().parse() {
+                    Ok(ms) => Ok(Step::Wait(Duration::from_millis(ms))),
+                    Err(_) => Err(invalid_step()),
+                },
+                _ => Err(invalid_step()),
+            }
+        })
+        .collect()
+}
+
+fn apply_sequence(steps: &[Step], port: &mut dyn SerialPort) -> Result<(), String> {
+    for step in steps {
+        let result = match *step {
+            Step::Dtr(level) => port.write_data_terminal_ready(level),
+            Step::Rts(level) => port.write_request_to_send(level),
+            Step::Wait(duration) => {
+                sleep(duration);
+                Ok(())
+            }
+        };
+        result.map_err(|error| format!("Failed to set DTR/RTS: {}", error.description))?;
+    }
+    Ok(())
+}
+
+impl Sequences {
+    // Applies the entry sequence, called right after opening a port
+    pub fn enter_bootloader(&self, port: &mut dyn SerialPort) -> Result<(), String> {
+        apply_sequence(&self.entry, port)
+    }
+
+    // Applies the exit sequence at the end of the session, failures are only
+    // reported since the session is over anyway
+    pub fn leave_bootloader(&self, port: &mut dyn SerialPort) {
+        if let Err(error) = apply_sequence(&self.exit, port) {
+            eprintln!("{error}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wait(ms: u64) -> Step {
+        Step::Wait(Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn steps_are_parsed() {
+        assert_eq!(
+            parse_sequence("dtr=1, RTS=1,wait=50,rts=0,").unwrap(),
+            [Step::Dtr(true), Step::Rts(true), wait(50), Step::Rts(false)]
+        );
+        assert_eq!(parse_sequence("").unwrap(), []);
+    }
+
+    #[test]
+    fn presets_are_expanded() {
+        assert_eq!(
+            parse_sequence("rts-reset-dtr-boot").unwrap(),
+            [
+                Step::Dtr(true),
+                Step::Rts(true),
+                wait(100),
+                Step::Rts(false),
+                wait(100),
+                Step::Dtr(false)
+            ]
+        );
+        assert_eq!(
+            parse_sequence("dtr-reset").unwrap(),
+            [
+                Step::Rts(false),
+                Step::Dtr(true),
+                wait(100),
+                Step::Dtr(false),
+                wait(100)
+            ]
+        );
+        for (name, _) in PRESETS {
+            assert!(parse_sequence(name).is_ok(), "{name}");
+        }
+    }
+
+    #[test]
+    fn invalid_steps_are_rejected() {
+        for sequence in [
+            "dtr=2",
+            "rts",
+            "cts=1",
+            "wait=-5",
+            "wait=abc",
+            "rts-reset-typo",
+        ] {
+            let error = parse_sequence(sequence).unwrap_err();
+            assert!(
+                error.starts_with("Invalid DTR/RTS sequence step"),
+                "{error}"
+            );
+        }
+    }
+
+    #[test]
+    fn missing_sequences_do_nothing() {
+        let sequences = parse_sequences(&ResetSettings::default()).unwrap();
+        assert!(sequences.entry.is_empty());
+        assert!(sequences.exit.is_empty());
+
+        let error = parse_sequences(&ResetSettings {
+            entry_sequence: Some("dtr=1".to_string()),
+            exit_sequence: Some("dtr=x".to_string()),
+        })
+        .err()
+        .unwrap();
+        assert!(error.contains("'dtr=x'"), "{error}");
+    }
+}
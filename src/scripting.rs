@@ -0,0 +1,224 @@
+use rhai::{Array, Blob, Dynamic, Engine, EvalAltResult, INT};
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::device::check_flash_range;
+use crate::flash::write_image;
+use crate::image::{parse_image, read_image_file, ImageFormat};
+use crate::imagecheck::check_image;
+use crate::memory::MemoryImage;
+use crate::protocol::{
+    get_crc, u32_to_u8, CMD_BL_FLASH_ERASE, CMD_BL_GET_DEV_ID, CMD_BL_GET_HELP,
+    CMD_BL_GET_RDP_LEVEL, CMD_BL_GET_RW_PROTECT, CMD_BL_GET_VER, CMD_BL_JMP_ADDR,
+    CMD_BL_SET_RW_PROTECT,
+};
+use crate::session::Session;
+
+type SharedSession = Rc<RefCell<Session>>;
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+// Runs a Rhai script with bindings for the bootloader commands, e.g.
+//
+//     if version() >= 0x11 {
+//         erase(2, 1);
+//     }
+//     write_file("app.bin", 0x08008000);
+//
+// A failed bootloader command raises a script error which can be handled
+// with try/catch inside the script. Returns true when the script completed.
+pub fn run_script(path: &Path, session: Session) -> bool {
+    let session = Rc::new(RefCell::new(session));
+    let engine = create_engine(session.clone());
+
+    let success = match engine.run_file(path.to_path_buf()) {
+        Ok(()) => true,
+        Err(error) => {
+            eprintln!("Script '{}' failed: {error}", path.display());
+            false
+        }
+    };
+
+    session.borrow_mut().leave_bootloader();
+    success
+}
+
+fn create_engine(session: SharedSession) -> Engine {
+    let mut engine = Engine::new();
+
+    let s = session.clone();
+    engine.register_fn("version", move || -> ScriptResult<INT> {
+        let reply = s.borrow_mut().execute_command(&CMD_BL_GET_VER, &[], &[])?;
+        Ok(first_byte(&reply)? as INT)
+    });
+
+    let s = session.clone();
+    engine.register_fn("commands", move || -> ScriptResult<Array> {
+        let reply = s.borrow_mut().execute_command(&CMD_BL_GET_HELP, &[], &[])?;
+        Ok(reply.iter().map(|cmd| Dynamic::from(*cmd as INT)).collect())
+    });
+
+    let s = session.clone();
+    engine.register_fn("dev_id", move || -> ScriptResult<INT> {
+        let reply = s
+            .borrow_mut()
+            .execute_command(&CMD_BL_GET_DEV_ID, &[], &[])?;
+        if reply.len() < 2 {
+            return Err("Invalid device id reply".into());
+        }
+        Ok(((reply[1] as INT) << 8) | reply[0] as INT)
+    });
+
+    let s = session.clone();
+    engine.register_fn("rdp", move || -> ScriptResult<INT> {
+        let reply = s
+            .borrow_mut()
+            .execute_command(&CMD_BL_GET_RDP_LEVEL, &[], &[])?;
+        Ok(first_byte(&reply)? as INT)
+    });
+
+    let s = session.clone();
+    engine.register_fn("jump", move |address: INT| -> ScriptResult<()> {
+        let address = to_address(address)?;
+        let args = [
+            u32_to_u8(address, 1),
+            u32_to_u8(address, 2),
+            u32_to_u8(address, 3),
+            u32_to_u8(address, 4),
+        ];
+        let reply = s
+            .borrow_mut()
+            .execute_command(&CMD_BL_JMP_ADDR, &args, &[])?;
+        match first_byte(&reply)? {
+            0 => {
+                let mut session = s.borrow_mut();
+                session.mark_app_running();
+                if session.run_monitor() {
+                    Ok(())
+                } else {
+                    Err(format!("Monitoring the application at 0x{address:08X} failed").into())
+                }
+            }
+            _ => Err(format!("Bootloader jump to address 0x{address:08X}: FAILURE").into()),
+        }
+    });
+
+    let s = session.clone();
+    engine.register_fn(
+        "erase",
+        move |sector: INT, count: INT| -> ScriptResult<()> {
+            if !(0..=7).contains(&sector) || count < 1 || sector + count > 8 {
+                return Err(
+                    format!("Can't erase {count} sectors starting at {sector} sector").into(),
+                );
+            }
+            let args = [sector as u8, count as u8];
+            let reply = s
+                .borrow_mut()
+                .execute_command(&CMD_BL_FLASH_ERASE, &args, &[])?;
+            match first_byte(&reply)? {
+                0 => Ok(()),
+                _ => Err("Bootloader flash erase: FAILURE".into()),
+            }
+        },
+    );
+
+    let s = session.clone();
+    engine.register_fn(
+        "read",
+        move |address: INT, length: INT| -> ScriptResult<Blob> {
+            let address = to_address(address)?;
+            if length < 0 {
+                return Err("Invalid read length".into());
+            }
+            check_flash_range(address, length as usize)?;
+            Ok(s.borrow_mut().read_memory(address, length as usize)?)
+        },
+    );
+
+    let s = session.clone();
+    engine.register_fn(
+        "write",
+        move |address: INT, data: Blob| -> ScriptResult<()> {
+            let address = to_address(address)?;
+            let mut image = MemoryImage::new();
+            image.add_segment(address, data)?;
+            Ok(write_image(&mut s.borrow_mut(), &image)?)
+        },
+    );
+
+    let s = session.clone();
+    engine.register_fn(
+        "write_file",
+        move |filename: &str, address: INT| -> ScriptResult<()> {
+            // the address is only used for raw binaries
+            let address = to_address(address)?;
+            let path = Path::new(filename);
+            let content = read_image_file(path)?;
+            // "-" reads stdin, the format is guessed from the content
+            let format = if filename == "-" {
+                ImageFormat::from_content(&content)
+            } else {
+                ImageFormat::from_path(path)
+            };
+            let image = parse_image(path, content, format, address)?;
+            let mut session = s.borrow_mut();
+            check_image(&image, session.settings.image_check)?;
+            Ok(write_image(&mut session, &image)?)
+        },
+    );
+
+    let s = session.clone();
+    engine.register_fn(
+        "protect",
+        move |sectors: Array, level: INT| -> ScriptResult<()> {
+            let mut mask = 0u8;
+            for sector in sectors {
+                match sector.as_int() {
+                    Ok(sector @ 0..=7) => mask |= 1 << sector,
+                    _ => return Err("Encountered invalid sector number".into()),
+                }
+            }
+            if !(1..=2).contains(&level) {
+                return Err("Incorrect protection level value".into());
+            }
+            let args = [mask, level as u8];
+            let reply = s
+                .borrow_mut()
+                .execute_command(&CMD_BL_SET_RW_PROTECT, &args, &[])?;
+            match first_byte(&reply)? {
+                1 => Ok(()),
+                _ => Err("Bootloader set r/w protection: FAILURE".into()),
+            }
+        },
+    );
+
+    let s = session;
+    engine.register_fn("protection", move || -> ScriptResult<Array> {
+        let reply = s
+            .borrow_mut()
+            .execute_command(&CMD_BL_GET_RW_PROTECT, &[], &[])?;
+        Ok(reply
+            .iter()
+            .map(|level| Dynamic::from(*level as INT))
+            .collect())
+    });
+
+    engine.register_fn("crc32", |data: Blob| get_crc(&data) as INT);
+
+    engine
+}
+
+fn first_byte(reply: &[u8]) -> ScriptResult<u8> {
+    match reply.first() {
+        Some(byte) => Ok(*byte),
+        None => Err("Empty bootloader reply".into()),
+    }
+}
+
+fn to_address(address: INT) -> ScriptResult<u32> {
+    match u32::try_from(address) {
+        Ok(address) => Ok(address),
+        Err(_) => Err(format!("Invalid address {address}").into()),
+    }
+}
@@ -0,0 +1,527 @@
+use clap::ValueEnum;
+use regex::Regex;
+use serde::Deserialize;
+use serialport::{
+    available_ports, ClearBuffer, DataBits, FlowControl, Parity, SerialPort, SerialPortInfo,
+    SerialPortType, StopBits,
+};
+use std::fs::{canonicalize, read_dir};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::Duration;
+
+use crate::protocol::{CMD_BL_GET_DEV_ID, CMD_BL_GET_VER};
+use crate::reset::Sequences;
+use crate::session::{Session, Settings, PROBE_TIMEOUT};
+
+const SERIAL_BY_ID_DIR: &str = "/dev/serial/by-id";
+
+#[derive(clap::Args)]
+pub struct PortSelection {
+    /// Serial port to open instead of choosing one from the list,
+    /// stable /dev/serial/by-id paths are accepted as well
+    #[arg(short, long)]
+    pub port: Option<String>,
+
+    /// Only consider USB serial devices with this vendor ID (hex)
+    #[arg(long, value_parser = parse_hex_u16)]
+    pub vid: Option<u16>,
+
+    /// Only consider USB serial devices with this product ID (hex)
+    #[arg(long, value_parser = parse_hex_u16)]
+    pub pid: Option<u16>,
+
+    /// Only consider USB serial devices with this serial number
+    #[arg(long)]
+    pub serial_number: Option<String>,
+
+    /// List the available serial devices and exit
+    #[arg(long)]
+    pub list_ports: bool,
+
+    /// Probe the available serial devices and only offer the ones where
+    /// the bootloader replies, with --list-ports only those are listed
+    #[arg(short, long, conflicts_with = "port")]
+    pub auto: bool,
+}
+
+// Serial line settings, given either on the command line or in the [serial]
+// table of the config file. Command line values take precedence.
+#[derive(clap::Args, Deserialize, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SerialSettings {
+    /// Baud rate of the bootloader UART [default: 115200]
+    #[arg(short, long)]
+    pub baud_rate: Option<u32>,
+
+    /// Number of data bits [default: 8]
+    #[arg(long, value_enum)]
+    pub data_bits: Option<DataBitsMode>,
+
+    /// Parity checking mode [default: none]
+    #[arg(long, value_enum)]
+    pub parity: Option<ParityMode>,
+
+    /// Number of stop bits [default: 1]
+    #[arg(long, value_enum)]
+    pub stop_bits: Option<StopBitsMode>,
+
+    /// Flow control mode [default: none]
+    #[arg(long, value_enum)]
+    pub flow_control: Option<FlowControlMode>,
+
+    /// Timeout in milliseconds for reading the bootloader replies [default: 2000]
+    #[arg(long)]
+    pub timeout_ms: Option<u64>,
+}
+
+// Given as a number, e.g. `--data-bits 7` or `data-bits = 7`
+#[derive(Clone, Copy, ValueEnum, Deserialize)]
+#[serde(try_from = "u8")]
+pub enum DataBitsMode {
+    #[value(name = "5")]
+    Five,
+    #[value(name = "6")]
+    Six,
+    #[value(name = "7")]
+    Seven,
+    #[value(name = "8")]
+    Eight,
+}
+
+impl TryFrom<u8> for DataBitsMode {
+    type Error = String;
+
+    fn try_from(bits: u8) -> Result<Self, Self::Error> {
+        match bits {
+            5 => Ok(DataBitsMode::Five),
+            6 => Ok(DataBitsMode::Six),
+            7 => Ok(DataBitsMode::Seven),
+            8 => Ok(DataBitsMode::Eight),
+            _ => Err(format!(
+                "invalid number of data bits {bits}, expected 5 to 8"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum, Deserialize)]
+#[serde(try_from = "u8")]
+pub enum StopBitsMode {
+    #[value(name = "1")]
+    One,
+    #[value(name = "2")]
+    Two,
+}
+
+impl TryFrom<u8> for StopBitsMode {
+    type Error = String;
+
+    fn try_from(bits: u8) -> Result<Self, Self::Error> {
+        match bits {
+            1 => Ok(StopBitsMode::One),
+            2 => Ok(StopBitsMode::Two),
+            _ => Err(format!(
+                "invalid number of stop bits {bits}, expected 1 or 2"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParityMode {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlowControlMode {
+    None,
+    Software,
+    Hardware,
+}
+
+impl SerialSettings {
+    pub fn or(self, fallback: SerialSettings) -> SerialSettings {
+        SerialSettings {
+            baud_rate: self.baud_rate.or(fallback.baud_rate),
+            data_bits: self.data_bits.or(fallback.data_bits),
+            parity: self.parity.or(fallback.parity),
+            stop_bits: self.stop_bits.or(fallback.stop_bits),
+            flow_control: self.flow_control.or(fallback.flow_control),
+            timeout_ms: self.timeout_ms.or(fallback.timeout_ms),
+        }
+    }
+
+    fn data_bits(&self) -> DataBits {
+        match self.data_bits.unwrap_or(DataBitsMode::Eight) {
+            DataBitsMode::Five => DataBits::Five,
+            DataBitsMode::Six => DataBits::Six,
+            DataBitsMode::Seven => DataBits::Seven,
+            DataBitsMode::Eight => DataBits::Eight,
+        }
+    }
+
+    fn parity(&self) -> Parity {
+        match self.parity.unwrap_or(ParityMode::None) {
+            ParityMode::None => Parity::None,
+            ParityMode::Odd => Parity::Odd,
+            ParityMode::Even => Parity::Even,
+        }
+    }
+
+    fn stop_bits(&self) -> StopBits {
+        match self.stop_bits.unwrap_or(StopBitsMode::One) {
+            StopBitsMode::One => StopBits::One,
+            StopBitsMode::Two => StopBits::Two,
+        }
+    }
+
+    fn flow_control(&self) -> FlowControl {
+        match self.flow_control.unwrap_or(FlowControlMode::None) {
+            FlowControlMode::None => FlowControl::None,
+            FlowControlMode::Software => FlowControl::Software,
+            FlowControlMode::Hardware => FlowControl::Hardware,
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms.unwrap_or(2000))
+    }
+}
+
+impl PortSelection {
+    fn has_filters(&self) -> bool {
+        self.vid.is_some() || self.pid.is_some() || self.serial_number.is_some()
+    }
+
+    fn matches(&self, port: &SerialPortInfo) -> bool {
+        if !self.has_filters() {
+            return true;
+        }
+
+        match &port.port_type {
+            SerialPortType::UsbPort(usb) => {
+                self.vid.is_none_or(|vid| vid == usb.vid)
+                    && self.pid.is_none_or(|pid| pid == usb.pid)
+                    && self
+                        .serial_number
+                        .as_ref()
+                        .is_none_or(|serial| usb.serial_number.as_ref() == Some(serial))
+            }
+            _ => false,
+        }
+    }
+}
+
+fn parse_hex_u16(input: &str) -> Result<u16, String> {
+    let input_lowercase = input.to_lowercase();
+    let input = input_lowercase.trim().trim_start_matches("0x");
+    u16::from_str_radix(input, 16).map_err(|_| format!("'{input}' is not a valid hex ID"))
+}
+
+fn get_available_serial_ports() -> Vec<SerialPortInfo> {
+    let pattern = Regex::new("/dev/tty[A-Za-z]*").unwrap();
+
+    let ports = available_ports().unwrap();
+    let mut available = Vec::new();
+    for port in ports {
+        if pattern.is_match(&port.port_name) {
+            available.push(port);
+        }
+    }
+
+    available
+}
+
+// Finds the stable /dev/serial/by-id link pointing at the given port
+fn get_serial_by_id_path(port_name: &str) -> Option<PathBuf> {
+    let port_path = canonicalize(port_name).ok()?;
+
+    read_dir(SERIAL_BY_ID_DIR)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|link| canonicalize(link).is_ok_and(|target| target == port_path))
+}
+
+// Resolves symlinks like /dev/serial/by-id/... to the device they point at
+fn resolve_port_name(name: &str) -> String {
+    let path = Path::new(name);
+    if path.is_symlink() {
+        if let Ok(target) = canonicalize(path) {
+            return target.to_string_lossy().to_string();
+        }
+    }
+
+    name.to_string()
+}
+
+fn describe_port(port: &SerialPortInfo) -> String {
+    let mut description = port.port_name.clone();
+
+    if let SerialPortType::UsbPort(usb) = &port.port_type {
+        let names: Vec<&str> = [&usb.manufacturer, &usb.product]
+            .into_iter()
+            .flatten()
+            .map(|name| name.as_str())
+            .collect();
+        if !names.is_empty() {
+            description += &format!(" - {}", names.join(" "));
+        }
+
+        description += &format!(" [{:04x}:{:04x}", usb.vid, usb.pid);
+        if let Some(serial_number) = &usb.serial_number {
+            description += &format!(", serial {serial_number}");
+        }
+        description += "]";
+    }
+
+    if let Some(by_id) = get_serial_by_id_path(&port.port_name) {
+        description += &format!("\n   {}", by_id.display());
+    }
+
+    description
+}
+
+fn display_serial_ports(ports: &[SerialPortInfo]) {
+    println!("Available serial devices:");
+    for (index, port) in ports.iter().enumerate() {
+        println!("{index}: {}", describe_port(port));
+    }
+}
+
+struct BootloaderInfo {
+    version: u8,
+    dev_id: u16,
+}
+
+// Sends CMD_BL_GET_VER and CMD_BL_GET_DEV_ID with a short timeout and
+// returns their results if a bootloader replied on the port. The probes use
+// a session of their own, which is never captured.
+fn probe_bootloader(
+    serial_port_name: &str,
+    serial_settings: &SerialSettings,
+    settings: &Settings,
+) -> Option<BootloaderInfo> {
+    let port = open_serial_port(serial_port_name, serial_settings, &settings.reset).ok()?;
+    port.clear(ClearBuffer::Input).ok()?;
+    let mut session = Session::new(port, settings.clone());
+
+    let version = session
+        .execute_command_with_timeout(&CMD_BL_GET_VER, &[], &[], PROBE_TIMEOUT)
+        .ok()?;
+    let dev_id = session
+        .execute_command_with_timeout(&CMD_BL_GET_DEV_ID, &[], &[], PROBE_TIMEOUT)
+        .ok()?;
+    if version.len() != 1 || dev_id.len() != 2 {
+        return None;
+    }
+
+    Some(BootloaderInfo {
+        version: version[0],
+        dev_id: (dev_id[1] as u16) << 8 | dev_id[0] as u16,
+    })
+}
+
+fn find_bootloader_ports(
+    serial_devices: Vec<SerialPortInfo>,
+    serial_settings: &SerialSettings,
+    settings: &Settings,
+) -> Vec<SerialPortInfo> {
+    println!("Probing {} serial devices...", serial_devices.len());
+
+    let mut found = Vec::new();
+    for port in serial_devices {
+        if let Some(info) = probe_bootloader(&port.port_name, serial_settings, settings) {
+            println!(
+                "{}: {}\n   bootloader version 0x{:02X}, device id 0x{:04X}",
+                found.len(),
+                describe_port(&port),
+                info.version,
+                info.dev_id
+            );
+            found.push(port);
+        }
+    }
+
+    found
+}
+
+pub fn list_serial_ports(
+    selection: &PortSelection,
+    serial_settings: &SerialSettings,
+    settings: &Settings,
+) {
+    let serial_devices: Vec<SerialPortInfo> = get_available_serial_ports()
+        .into_iter()
+        .filter(|port| selection.matches(port))
+        .collect();
+
+    if selection.auto {
+        if find_bootloader_ports(serial_devices, serial_settings, settings).is_empty() {
+            println!("No bootloader replied on any of the serial devices");
+        }
+    } else {
+        display_serial_ports(&serial_devices);
+    }
+}
+
+pub fn open_serial_port(
+    serial_port_name: &str,
+    settings: &SerialSettings,
+    sequences: &Sequences,
+) -> Result<Box<dyn SerialPort>, String> {
+    let mut port = serialport::new(serial_port_name, settings.baud_rate.unwrap_or(115200))
+        .data_bits(settings.data_bits())
+        .parity(settings.parity())
+        .stop_bits(settings.stop_bits())
+        .flow_control(settings.flow_control())
+        .timeout(settings.timeout())
+        .open()
+        .map_err(|error| format!("Failed to open {serial_port_name}: {}", error.description))?;
+
+    sequences.enter_bootloader(port.as_mut()).map_err(|error| {
+        format!("Failed to enter the bootloader on {serial_port_name}: {error}")
+    })?;
+    Ok(port)
+}
+
+// Opens the port given on the command line, the only port matching the USB
+// filters or lets the user choose one of the matching ports
+pub fn select_serial_port(
+    selection: &PortSelection,
+    serial_settings: &SerialSettings,
+    settings: &Settings,
+) -> Box<dyn SerialPort> {
+    if let Some(name) = &selection.port {
+        return match open_serial_port(&resolve_port_name(name), serial_settings, &settings.reset) {
+            Ok(port) => port,
+            Err(error) => {
+                eprintln!("{error}");
+                exit(1);
+            }
+        };
+    }
+
+    let serial_devices: Vec<SerialPortInfo> = get_available_serial_ports()
+        .into_iter()
+        .filter(|port| selection.matches(port))
+        .collect();
+
+    if serial_devices.is_empty() {
+        if selection.has_filters() {
+            eprintln!("No serial devices match the given USB filters!");
+            exit(1);
+        }
+        eprintln!("No available serial devices!");
+        exit(0);
+    }
+
+    if selection.auto {
+        let bootloader_devices = find_bootloader_ports(serial_devices, serial_settings, settings);
+        return match bootloader_devices.len() {
+            0 => {
+                eprintln!("No bootloader replied on any of the serial devices!");
+                eprintln!("Make sure the device is in bootloader mode.");
+                exit(1);
+            }
+            1 => match open_serial_port(
+                &bootloader_devices[0].port_name,
+                serial_settings,
+                &settings.reset,
+            ) {
+                Ok(port) => port,
+                Err(error) => {
+                    eprintln!("{error}");
+                    exit(1);
+                }
+            },
+            _ => choose_serial_port(&bootloader_devices, serial_settings, &settings.reset),
+        };
+    }
+
+    if selection.has_filters() && serial_devices.len() == 1 {
+        println!("Using {}", describe_port(&serial_devices[0]));
+        return match open_serial_port(
+            &serial_devices[0].port_name,
+            serial_settings,
+            &settings.reset,
+        ) {
+            Ok(port) => port,
+            Err(error) => {
+                eprintln!("{error}");
+                exit(1);
+            }
+        };
+    }
+
+    display_serial_ports(&serial_devices);
+    choose_serial_port(&serial_devices, serial_settings, &settings.reset)
+}
+
+fn choose_serial_port(
+    serial_devices: &[SerialPortInfo],
+    settings: &SerialSettings,
+    sequences: &Sequences,
+) -> Box<dyn SerialPort> {
+    print!("Choose your device from the list: ");
+    io::stdout().flush().unwrap();
+
+    loop {
+        let mut input = String::new();
+        io::stdin().read_line(&mut input).unwrap();
+        let input = input.trim();
+
+        // the device can be chosen by its index, name or by-id path
+        let serial_port_name = match input.parse::<usize>() {
+            Ok(index) if index < serial_devices.len() => serial_devices[index].port_name.clone(),
+            _ => resolve_port_name(input),
+        };
+
+        if !serial_devices
+            .iter()
+            .any(|port| port.port_name == serial_port_name)
+        {
+            eprintln!("'{input}' not found in the list of available ports");
+            print!("Try again: ");
+            io::stdout().flush().unwrap();
+            continue;
+        }
+
+        match open_serial_port(&serial_port_name, settings, sequences) {
+            Ok(port) => return port,
+            Err(error) => {
+                eprintln!("{error}");
+                print!("Try again: ");
+                io::stdout().flush().unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_file_line_settings_are_range_checked() {
+        let settings: SerialSettings = toml::from_str("data-bits = 7\nstop-bits = 2").unwrap();
+        assert!(matches!(settings.data_bits(), DataBits::Seven));
+        assert!(matches!(settings.stop_bits(), StopBits::Two));
+
+        for content in [
+            "data-bits = 9",
+            "data-bits = 4",
+            "stop-bits = 0",
+            "stop-bits = 3",
+        ] {
+            let error = toml::from_str::<SerialSettings>(content).err().unwrap();
+            assert!(error.to_string().contains("expected"), "{error}");
+        }
+    }
+}
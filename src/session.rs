@@ -0,0 +1,493 @@
+use serialport::{ClearBuffer, SerialPort};
+use std::io::{self, Write};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::bitcheck::BitCheck;
+use crate::capture::Capture;
+use crate::device::FLASH_SECTORS;
+use crate::imagecheck::ImageCheck;
+use crate::monitor::{run_monitor, Monitor};
+use crate::protocol::{
+    find_bootloader_command, get_crc, u32_to_u8, BootloaderCommand, CMD_BL_FLASH_ERASE,
+    CMD_BL_GET_DEV_ID, CMD_BL_GET_HELP, CMD_BL_GET_RW_PROTECT, CMD_BL_GET_VER, CMD_BL_MEM_READ,
+    CMD_BL_MEM_WRITE, MAX_DATA_CHUNK_SIZE,
+};
+use crate::reset::Sequences;
+use crate::timeouts::Timeouts;
+use crate::trace::Trace;
+
+// time to wait for the bootloader reply when probing ports in --auto mode
+// or while waiting for the bootloader to come back after a jump
+pub const PROBE_TIMEOUT: Duration = Duration::from_millis(200);
+
+// time between two BL_GET_VER probes while the application is running
+const REENTRY_PROBE_INTERVAL: Duration = Duration::from_millis(500);
+
+// how long to wait for the bootloader to come back unless
+// --reentry-timeout-ms is given
+pub const DEFAULT_REENTRY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Settings from the command line and the config file, fixed for the whole
+// session
+#[derive(Clone)]
+pub struct Settings {
+    // the bootloader appends a CRC to its replies
+    pub reply_crc: bool,
+    // the gaps between image segments are written as 0xFF instead of being
+    // skipped
+    pub fill_gaps: bool,
+    // writes are padded with 0xFF to multiples of the programming unit size,
+    // misaligned start addresses are only padded with fix_alignment
+    pub program_unit: u32,
+    pub fix_alignment: bool,
+    pub bit_check: BitCheck,
+    pub image_check: ImageCheck,
+    pub timeouts: Timeouts,
+    // how long to wait for the bootloader to come back after a jump
+    pub reentry_timeout: Duration,
+    pub reset: Sequences,
+    pub monitor: Option<Monitor>,
+    pub trace: Option<Trace>,
+}
+
+// A connection to the bootloader and what is known about it
+pub struct Session {
+    pub port: Box<dyn SerialPort>,
+    pub settings: Settings,
+    pub capture: Option<Capture>,
+    // opcodes advertised by the bootloader in its BL_GET_HELP reply, every
+    // command is assumed to be supported until they are known
+    pub supported_commands: Option<Vec<u8>>,
+    // set after a successful jump until the bootloader replies again
+    app_running: bool,
+}
+
+impl Session {
+    pub fn new(port: Box<dyn SerialPort>, settings: Settings) -> Session {
+        Session {
+            port,
+            settings,
+            capture: None,
+            supported_commands: None,
+            app_running: false,
+        }
+    }
+
+    pub fn is_command_supported(&self, code: u8) -> bool {
+        self.supported_commands
+            .as_ref()
+            .is_none_or(|supported| supported.contains(&code))
+    }
+
+    pub fn mark_app_running(&mut self) {
+        self.app_running = true;
+    }
+
+    // Returns true right away when --monitor is not enabled
+    pub fn run_monitor(&mut self) -> bool {
+        match &self.settings.monitor {
+            Some(monitor) => run_monitor(monitor, self.port.as_mut()),
+            None => true,
+        }
+    }
+
+    pub fn leave_bootloader(&mut self) {
+        self.settings.reset.leave_bootloader(self.port.as_mut());
+    }
+
+    pub fn clear_input(&mut self) {
+        if let Err(error) = self.port.clear(ClearBuffer::Input) {
+            eprintln!("Failed to clear the input buffer! {}", error.description);
+        }
+    }
+
+    fn calc_checksum_and_send(&mut self, data: &mut [u8]) -> Option<std::io::Error> {
+        let cmd_len = data[0];
+        let mut crc_buffer = [0u8; 4];
+        // calculate crc on bytes [0 to CMD_BL_X_LEN - 4)
+        // to properly calculate the crc, it expects the first byte to be
+        // the length to follow which means we need to subtract one because
+        // we don't count the length itself
+        data[0] -= 1;
+        let crc32 = get_crc(&data[0..(cmd_len - 4) as usize]);
+        crc_buffer[0] = u32_to_u8(crc32, 1);
+        crc_buffer[1] = u32_to_u8(crc32, 2);
+        crc_buffer[2] = u32_to_u8(crc32, 3);
+        crc_buffer[3] = u32_to_u8(crc32, 4);
+
+        // append crc_buffer to data
+        let data = [&data[0..(cmd_len - 4) as usize], &crc_buffer[..]].concat();
+        if let Some(trace) = &self.settings.trace {
+            trace.tx(&data[0..(cmd_len as usize)]);
+        }
+        if let Some(capture) = &mut self.capture {
+            capture.tx(&data[0..(cmd_len as usize)]);
+        }
+
+        if let Err(error) = self.port.write_all(&data[0..1]) {
+            return Some(error);
+        }
+
+        if let Err(error) = self.port.write_all(&data[1..((cmd_len) as usize)]) {
+            return Some(error);
+        }
+
+        None
+    }
+
+    pub fn send_command(&mut self, data: &mut [u8], timeout: Duration) -> Option<std::io::Error> {
+        // the reply timeout depends on the command, e.g. erasing multiple
+        // 128K sectors takes much longer than reading the bootloader version
+        if let Err(error) = self.port.set_timeout(timeout) {
+            return Some(error.into());
+        }
+
+        self.calc_checksum_and_send(data)
+    }
+
+    // Same as read_exact, but logs the received bytes to the trace, also the
+    // ones received before a timeout
+    fn read_reply_bytes(&mut self, buffer: &mut [u8], code: u8, part: &str) -> io::Result<()> {
+        let mut received = 0;
+        let result = loop {
+            if received == buffer.len() {
+                break Ok(());
+            }
+            match self.port.read(&mut buffer[received..]) {
+                Ok(0) => break Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(count) => received += count,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => break Err(error),
+            }
+        };
+
+        if let Some(trace) = &self.settings.trace {
+            trace.rx(code, part, &buffer[..received], result.as_ref().err());
+        }
+        if let Some(capture) = &mut self.capture {
+            capture.rx(&buffer[..received]);
+        }
+        result
+    }
+
+    // Reads the reply to a command with the given code and arguments
+    //
+    // 0xBB | length | reply[length] | CRC32 (only with reply CRC checks enabled)
+    //
+    // The reply CRC is calculated over the length byte and the reply bytes the
+    // same way as for the commands and sent least significant byte first.
+    pub fn read_bootloader_reply(&mut self, code: u8, args: &[u8]) -> Result<Vec<u8>, String> {
+        let mut rcv_buffer = vec![0u8; 2];
+        if let Err(error) = self.read_reply_bytes(&mut rcv_buffer, code, "header") {
+            return Err(format!("Failed to read the bootloader reply: {error}"));
+        }
+
+        if rcv_buffer[0] == 0xEE {
+            return Err("CRC verification failed!".to_string());
+        } else if rcv_buffer[0] != 0xBB {
+            return Err("Unknown reply!".to_string());
+        }
+
+        let mut reply = vec![0u8; rcv_buffer[1] as usize];
+        if let Err(error) = self.read_reply_bytes(&mut reply, code, "reply") {
+            return Err(format!("Failed to read the bootloader reply: {error}"));
+        }
+
+        if self.settings.reply_crc {
+            let mut crc_buffer = [0u8; 4];
+            if let Err(error) = self.read_reply_bytes(&mut crc_buffer, code, "crc") {
+                return Err(format!("Failed to read the bootloader reply CRC: {error}"));
+            }
+
+            let received_crc = u32::from_le_bytes(crc_buffer);
+            let calculated_crc = get_crc(&[&rcv_buffer[1..], &reply[..]].concat());
+            if received_crc != calculated_crc {
+                return Err(format!(
+                    "Reply CRC mismatch: received 0x{received_crc:08X}, calculated 0x{calculated_crc:08X}!"
+                ));
+            }
+        }
+
+        validate_reply_length(code, args, &reply)?;
+
+        Ok(reply)
+    }
+
+    pub fn execute_command(
+        &mut self,
+        command: &BootloaderCommand,
+        args: &[u8],
+        payload: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        self.wait_for_bootloader()?;
+        let timeout = self.settings.timeouts.reply_timeout(command.code, args);
+        self.execute_command_with_timeout(command, args, payload, timeout)
+    }
+
+    pub fn execute_command_with_timeout(
+        &mut self,
+        command: &BootloaderCommand,
+        args: &[u8],
+        payload: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, String> {
+        if !self.is_command_supported(command.code) {
+            return Err(format!(
+                "{} is not supported by the bootloader!",
+                command.name
+            ));
+        }
+
+        // the payload is the variable length data following the fixed
+        // arguments, e.g. the bytes to write for CMD_BL_MEM_WRITE
+        let mut data_buffer = vec![0u8; 255];
+        data_buffer[0] = command.length + payload.len() as u8;
+        data_buffer[1] = command.code;
+        data_buffer[2..(args.len() + 2)].copy_from_slice(args);
+        data_buffer[(args.len() + 2)..(args.len() + payload.len() + 2)].copy_from_slice(payload);
+
+        if let Some(error) = self.send_command(&mut data_buffer, timeout) {
+            return Err(format!("Failed to send the command: '{}'", error.kind()));
+        }
+
+        self.read_bootloader_reply(command.code, args)
+    }
+
+    // Waits for the device to reset back into the bootloader after a jump by
+    // probing it with BL_GET_VER. Returns right away when no application was
+    // started. After a timeout, or when the bootloader can't be probed, the
+    // error is reported once and the session continues without waiting.
+    pub fn wait_for_bootloader(&mut self) -> Result<(), String> {
+        if !self.app_running {
+            return Ok(());
+        }
+
+        if !self.is_command_supported(CMD_BL_GET_VER.code) {
+            self.app_running = false;
+            return Err(
+                "The bootloader does not support BL_GET_VER, its re-entry can't be detected!"
+                    .to_string(),
+            );
+        }
+
+        let timeout = self.settings.reentry_timeout;
+        println!(
+            "Waiting up to {:.1} s for the device to re-enter the bootloader...",
+            timeout.as_secs_f32()
+        );
+        let start = Instant::now();
+
+        loop {
+            // discard whatever the application sent meanwhile
+            let _ = self.port.clear(ClearBuffer::Input);
+            if self
+                .execute_command_with_timeout(&CMD_BL_GET_VER, &[], &[], PROBE_TIMEOUT)
+                .is_ok()
+            {
+                println!(
+                    "Bootloader is back after {:.1} s",
+                    start.elapsed().as_secs_f32()
+                );
+                self.app_running = false;
+                return Ok(());
+            }
+
+            if start.elapsed() >= timeout {
+                self.app_running = false;
+                return Err(format!(
+                    "The device did not re-enter the bootloader within {} ms!",
+                    timeout.as_millis()
+                ));
+            }
+
+            sleep(REENTRY_PROBE_INTERVAL);
+        }
+    }
+
+    pub fn read_memory(&mut self, base_address: u32, length: usize) -> Result<Vec<u8>, String> {
+        let mut memory = Vec::with_capacity(length);
+
+        while memory.len() < length {
+            let address = base_address + memory.len() as u32;
+            let chunk_size = (length - memory.len()).min(MAX_DATA_CHUNK_SIZE as usize) as u8;
+            let args = [
+                u32_to_u8(address, 1),
+                u32_to_u8(address, 2),
+                u32_to_u8(address, 3),
+                u32_to_u8(address, 4),
+                chunk_size,
+            ];
+
+            let reply = self.execute_command(&CMD_BL_MEM_READ, &args, &[])?;
+            if reply.first() != Some(&1) || reply.len() != chunk_size as usize + 1 {
+                return Err(format!(
+                    "Bootloader memory read at 0x{address:08X}: FAILURE"
+                ));
+            }
+
+            memory.extend_from_slice(&reply[1..]);
+        }
+
+        Ok(memory)
+    }
+
+    pub fn write_memory(&mut self, base_address: u32, bytes: &[u8]) -> Result<(), String> {
+        for (index, chunk) in bytes.chunks(MAX_DATA_CHUNK_SIZE as usize).enumerate() {
+            let address = base_address + (index * MAX_DATA_CHUNK_SIZE as usize) as u32;
+            let args = [
+                u32_to_u8(address, 1),
+                u32_to_u8(address, 2),
+                u32_to_u8(address, 3),
+                u32_to_u8(address, 4),
+                chunk.len() as u8,
+            ];
+
+            let reply = self.execute_command(&CMD_BL_MEM_WRITE, &args, chunk)?;
+            if reply.first() != Some(&1) {
+                return Err(format!(
+                    "Bootloader memory write at 0x{address:08X}: FAILURE"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn erase_sectors(&mut self, first_sector: u8, count: u8) -> Result<(), String> {
+        let reply = self.execute_command(&CMD_BL_FLASH_ERASE, &[first_sector, count], &[])?;
+        match reply.first() {
+            Some(0) => Ok(()),
+            _ => Err(format!(
+                "Bootloader flash erase of {count} sectors starting at sector {first_sector}: FAILURE"
+            )),
+        }
+    }
+}
+
+// Returns the allowed lengths of the reply to a command with the given
+// arguments or None if the length is not known in advance
+fn expected_reply_lengths(code: u8, args: &[u8]) -> Option<Vec<usize>> {
+    if code == CMD_BL_GET_HELP.code {
+        None
+    } else if code == CMD_BL_GET_DEV_ID.code {
+        Some(vec![2])
+    } else if code == CMD_BL_MEM_READ.code {
+        // only the status byte is sent back when reading fails
+        Some(vec![1, args[4] as usize + 1])
+    } else if code == CMD_BL_GET_RW_PROTECT.code {
+        Some(vec![FLASH_SECTORS.len()])
+    } else {
+        Some(vec![1])
+    }
+}
+
+fn validate_reply_length(code: u8, args: &[u8], reply: &[u8]) -> Result<(), String> {
+    let name = find_bootloader_command(code).map_or("unknown command", |command| command.name);
+
+    match expected_reply_lengths(code, args) {
+        Some(lengths) if !lengths.contains(&reply.len()) => {
+            let expected: Vec<String> = lengths.iter().map(|length| length.to_string()).collect();
+            Err(format!(
+                "Invalid {name} reply length: expected {} bytes, received {}!",
+                expected.join(" or "),
+                reply.len()
+            ))
+        }
+        None if reply.is_empty() => Err(format!("Invalid {name} reply length: received 0 bytes!")),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fakeport::fake_session;
+    use crate::protocol::{CMD_BL_GET_VER, CMD_BL_MEM_READ};
+
+    #[test]
+    fn reply_lengths_match_the_command() {
+        assert!(validate_reply_length(CMD_BL_GET_VER.code, &[], &[0x10]).is_ok());
+        assert!(validate_reply_length(CMD_BL_GET_DEV_ID.code, &[], &[0x21, 0x04]).is_ok());
+        assert!(validate_reply_length(CMD_BL_GET_HELP.code, &[], &[0xA1, 0xA2]).is_ok());
+        assert!(validate_reply_length(CMD_BL_GET_RW_PROTECT.code, &[], &[0; 8]).is_ok());
+
+        let args = [0x00, 0x00, 0x00, 0x08, 4];
+        assert!(validate_reply_length(CMD_BL_MEM_READ.code, &args, &[1, 0, 0, 0, 0]).is_ok());
+        assert!(validate_reply_length(CMD_BL_MEM_READ.code, &args, &[0]).is_ok());
+    }
+
+    #[test]
+    fn invalid_reply_lengths_are_rejected() {
+        assert!(validate_reply_length(CMD_BL_GET_VER.code, &[], &[]).is_err());
+        assert!(validate_reply_length(CMD_BL_GET_VER.code, &[], &[0x10, 0x00]).is_err());
+        assert!(validate_reply_length(CMD_BL_GET_DEV_ID.code, &[], &[0x21]).is_err());
+        assert!(validate_reply_length(CMD_BL_GET_HELP.code, &[], &[]).is_err());
+        assert!(validate_reply_length(CMD_BL_GET_RW_PROTECT.code, &[], &[0; 7]).is_err());
+
+        let args = [0x00, 0x00, 0x00, 0x08, 4];
+        let error = validate_reply_length(CMD_BL_MEM_READ.code, &args, &[1, 0, 0]).unwrap_err();
+        assert!(
+            error.contains("expected 1 or 5 bytes, received 3"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn reply_with_valid_crc_is_accepted() {
+        let (mut session, bootloader) = fake_session();
+        session.settings.reply_crc = true;
+        bootloader.lock().unwrap().reply_crc = true;
+
+        let reply = session.execute_command(&CMD_BL_GET_VER, &[], &[]);
+        assert_eq!(reply, Ok(vec![0x10]));
+    }
+
+    #[test]
+    fn reply_with_corrupt_crc_is_rejected() {
+        let (mut session, bootloader) = fake_session();
+        session.settings.reply_crc = true;
+        bootloader.lock().unwrap().reply_crc = true;
+        bootloader.lock().unwrap().corrupt_crc = true;
+
+        let error = session
+            .execute_command(&CMD_BL_GET_VER, &[], &[])
+            .unwrap_err();
+        assert!(error.starts_with("Reply CRC mismatch"), "{error}");
+    }
+
+    #[test]
+    fn reply_without_expected_crc_is_rejected() {
+        let (mut session, _bootloader) = fake_session();
+        session.settings.reply_crc = true;
+
+        let error = session
+            .execute_command(&CMD_BL_GET_VER, &[], &[])
+            .unwrap_err();
+        assert!(
+            error.starts_with("Failed to read the bootloader reply CRC"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn reply_of_wrong_length_is_rejected() {
+        let (mut session, bootloader) = fake_session();
+        bootloader.lock().unwrap().replies.push_back(vec![0x21]);
+
+        let error = session
+            .execute_command(&CMD_BL_GET_DEV_ID, &[], &[])
+            .unwrap_err();
+        assert!(error.starts_with("Invalid"), "{error}");
+    }
+
+    #[test]
+    fn memory_is_written_and_read_back_in_chunks() {
+        let (mut session, bootloader) = fake_session();
+        let bytes: Vec<u8> = (0..600).map(|byte| byte as u8).collect();
+
+        session.write_memory(0x08008000, &bytes).unwrap();
+        assert_eq!(session.read_memory(0x08008000, bytes.len()), Ok(bytes));
+        assert!(bootloader.lock().unwrap().frames.len() > 2);
+    }
+}
@@ -0,0 +1,189 @@
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::device::FLASH_SECTORS;
+use crate::protocol::{
+    CMD_BL_FLASH_ERASE, CMD_BL_GET_DEV_ID, CMD_BL_GET_HELP, CMD_BL_GET_RDP_LEVEL,
+    CMD_BL_GET_RW_PROTECT, CMD_BL_GET_VER, CMD_BL_JMP_ADDR, CMD_BL_MEM_READ, CMD_BL_MEM_WRITE,
+    CMD_BL_SET_RW_PROTECT,
+};
+
+// worst case sector erase time of the STM32F4 with x8 parallelism is
+// 4 seconds for a 128K sector
+const DEFAULT_ERASE_MS_PER_KB: u64 = 32;
+
+// Reply timeouts in milliseconds for each command, given in the [timeouts]
+// table of the config file. Commands without a timeout use the serial
+// timeout-ms setting.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TimeoutSettings {
+    pub version: Option<u64>,
+    pub commands: Option<u64>,
+    pub dev_id: Option<u64>,
+    pub rdp: Option<u64>,
+    pub jmp: Option<u64>,
+    pub write: Option<u64>,
+    pub read: Option<u64>,
+    pub set_prot: Option<u64>,
+    pub get_prot: Option<u64>,
+    // when set, used for every erase regardless of the sectors being erased
+    pub erase: Option<u64>,
+    // otherwise the erase timeout is the default timeout plus this time
+    // for every kilobyte being erased
+    pub erase_ms_per_kb: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct Timeouts {
+    default: Duration,
+    settings: TimeoutSettings,
+}
+
+impl Timeouts {
+    pub fn new(default: Duration, settings: TimeoutSettings) -> Timeouts {
+        Timeouts { default, settings }
+    }
+
+    // Returns how long to wait for the reply to a command with the given
+    // code and arguments
+    pub fn reply_timeout(&self, code: u8, args: &[u8]) -> Duration {
+        let settings = &self.settings;
+
+        if code == CMD_BL_FLASH_ERASE.code {
+            return settings
+                .erase
+                .map(Duration::from_millis)
+                .unwrap_or_else(|| self.default + erase_time(settings, args));
+        }
+
+        let timeout = if code == CMD_BL_GET_VER.code {
+            settings.version
+        } else if code == CMD_BL_GET_HELP.code {
+            settings.commands
+        } else if code == CMD_BL_GET_DEV_ID.code {
+            settings.dev_id
+        } else if code == CMD_BL_GET_RDP_LEVEL.code {
+            settings.rdp
+        } else if code == CMD_BL_JMP_ADDR.code {
+            settings.jmp
+        } else if code == CMD_BL_MEM_WRITE.code {
+            settings.write
+        } else if code == CMD_BL_MEM_READ.code {
+            settings.read
+        } else if code == CMD_BL_SET_RW_PROTECT.code {
+            settings.set_prot
+        } else if code == CMD_BL_GET_RW_PROTECT.code {
+            settings.get_prot
+        } else {
+            None
+        };
+
+        timeout.map(Duration::from_millis).unwrap_or(self.default)
+    }
+}
+
+// Expected time to erase the sectors given in the CMD_BL_FLASH_ERASE
+// arguments: base sector number followed by the number of sectors
+fn erase_time(settings: &TimeoutSettings, args: &[u8]) -> Duration {
+    let (base_sector, num_of_sectors) = match args {
+        [base_sector, num_of_sectors, ..] => (*base_sector as usize, *num_of_sectors as usize),
+        _ => return Duration::ZERO,
+    };
+
+    let erase_size: u32 = FLASH_SECTORS
+        .iter()
+        .skip(base_sector)
+        .take(num_of_sectors)
+        .map(|sector| sector.size)
+        .sum();
+
+    let ms_per_kb = settings.erase_ms_per_kb.unwrap_or(DEFAULT_ERASE_MS_PER_KB);
+
+    Duration::from_millis(ms_per_kb * (erase_size / 1024) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULT: Duration = Duration::from_millis(2000);
+
+    #[test]
+    fn commands_without_a_timeout_use_the_default() {
+        let timeouts = Timeouts::new(DEFAULT, TimeoutSettings::default());
+        assert_eq!(timeouts.reply_timeout(CMD_BL_GET_VER.code, &[]), DEFAULT);
+        assert_eq!(
+            timeouts.reply_timeout(CMD_BL_MEM_READ.code, &[0; 5]),
+            DEFAULT
+        );
+        assert_eq!(timeouts.reply_timeout(0xFF, &[]), DEFAULT);
+    }
+
+    #[test]
+    fn commands_use_their_own_timeout() {
+        let settings = TimeoutSettings {
+            write: Some(1000),
+            get_prot: Some(300),
+            ..TimeoutSettings::default()
+        };
+        let timeouts = Timeouts::new(DEFAULT, settings);
+        assert_eq!(
+            timeouts.reply_timeout(CMD_BL_MEM_WRITE.code, &[0; 5]),
+            Duration::from_millis(1000)
+        );
+        assert_eq!(
+            timeouts.reply_timeout(CMD_BL_GET_RW_PROTECT.code, &[]),
+            Duration::from_millis(300)
+        );
+        assert_eq!(
+            timeouts.reply_timeout(CMD_BL_MEM_READ.code, &[0; 5]),
+            DEFAULT
+        );
+    }
+
+    #[test]
+    fn erase_timeout_scales_with_the_erased_sectors() {
+        let timeouts = Timeouts::new(DEFAULT, TimeoutSettings::default());
+        // sector 0 is 16K, sectors 5 to 7 are 128K each
+        assert_eq!(
+            timeouts.reply_timeout(CMD_BL_FLASH_ERASE.code, &[0, 1]),
+            DEFAULT + Duration::from_millis(16 * 32)
+        );
+        assert_eq!(
+            timeouts.reply_timeout(CMD_BL_FLASH_ERASE.code, &[5, 3]),
+            DEFAULT + Duration::from_millis(384 * 32)
+        );
+        // whole flash
+        assert_eq!(
+            timeouts.reply_timeout(CMD_BL_FLASH_ERASE.code, &[0, 8]),
+            DEFAULT + Duration::from_millis(512 * 32)
+        );
+    }
+
+    #[test]
+    fn erase_time_uses_the_configured_rate() {
+        let settings = TimeoutSettings {
+            erase_ms_per_kb: Some(10),
+            ..TimeoutSettings::default()
+        };
+        assert_eq!(erase_time(&settings, &[4, 1]), Duration::from_millis(640));
+        // sectors past the end of flash don't add anything
+        assert_eq!(erase_time(&settings, &[7, 5]), Duration::from_millis(1280));
+        assert_eq!(erase_time(&settings, &[]), Duration::ZERO);
+    }
+
+    #[test]
+    fn fixed_erase_timeout_overrides_the_rate() {
+        let settings = TimeoutSettings {
+            erase: Some(30000),
+            erase_ms_per_kb: Some(10),
+            ..TimeoutSettings::default()
+        };
+        let timeouts = Timeouts::new(DEFAULT, settings);
+        assert_eq!(
+            timeouts.reply_timeout(CMD_BL_FLASH_ERASE.code, &[5, 3]),
+            Duration::from_millis(30000)
+        );
+    }
+}
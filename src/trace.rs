@@ -0,0 +1,103 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::protocol::find_bootloader_command;
+
+// Log of all frames exchanged with the bootloader, the clones of a trace
+// write to the same output, e.g. while probing ports for --auto
+#[derive(Clone)]
+pub struct Trace {
+    output: Rc<RefCell<Box<dyn Write>>>,
+    start: Instant,
+}
+
+// Starts logging all frames to the given file, "-" logs to stderr
+pub fn create_trace(path: &Path) -> Result<Trace, String> {
+    let mut output: Box<dyn Write> = if path == Path::new("-") {
+        Box::new(io::stderr())
+    } else {
+        let file = File::create(path).map_err(|error| {
+            format!("Failed to create trace file '{}': {error}", path.display())
+        })?;
+        Box::new(file)
+    };
+
+    let unix_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let _ = writeln!(
+        output,
+        "# trace started at {}.{:03} (unix time), timestamps are seconds since then",
+        unix_time.as_secs(),
+        unix_time.subsec_millis()
+    );
+
+    Ok(Trace {
+        output: Rc::new(RefCell::new(output)),
+        start: Instant::now(),
+    })
+}
+
+fn command_name(code: u8) -> String {
+    match find_bootloader_command(code) {
+        Some(command) => command.name.to_string(),
+        None => format!("0x{code:02X}"),
+    }
+}
+
+// Formats bytes as space separated hex, as in trace and capture files
+pub fn to_hex(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{byte:02X}")).collect();
+    hex.join(" ")
+}
+
+impl Trace {
+    fn write_line(&self, line: &str) {
+        let mut output = self.output.borrow_mut();
+        let elapsed = self.start.elapsed();
+        let _ = writeln!(
+            output,
+            "[{:>4}.{:06}] {line}",
+            elapsed.as_secs(),
+            elapsed.subsec_micros()
+        );
+        let _ = output.flush();
+    }
+
+    // Logs a complete command frame: length byte, command code, arguments
+    // and CRC
+    pub fn tx(&self, frame: &[u8]) {
+        if frame.len() < 6 {
+            self.write_line(&format!("TX {:<21} | {}", "invalid frame", to_hex(frame)));
+            return;
+        }
+
+        let crc = u32::from_le_bytes(frame[frame.len() - 4..].try_into().unwrap());
+        self.write_line(&format!(
+            "TX {:<21} len={:<3} crc=0x{crc:08X} | {}",
+            command_name(frame[1]),
+            frame[0],
+            to_hex(frame)
+        ));
+    }
+
+    // Logs the bytes read for a part of the reply to the given command,
+    // including the bytes received before an error occurred
+    pub fn rx(&self, code: u8, part: &str, bytes: &[u8], error: Option<&io::Error>) {
+        let mut line = format!(
+            "RX {:<21} {part:<6} len={:<3} | {}",
+            command_name(code),
+            bytes.len(),
+            to_hex(bytes)
+        );
+        if let Some(error) = error {
+            line += &format!(" ({error})");
+        }
+
+        self.write_line(&line);
+    }
+}